@@ -1,5 +1,6 @@
 use alloc::boxed::Box;
 use alloc::vec;
+use core::ptr::NonNull;
 use core::{fmt, slice};
 
 use limine::framebuffer::Framebuffer;
@@ -17,6 +18,11 @@ pub fn init(fbman: FrameBufferManager) {
     *guard = Some(fbman);
 }
 
+/// Whether `init` was called, i.e. whether `print!` works.
+pub fn initialized() -> bool {
+    without_interrupts(|| FBMAN.lock().is_some())
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -80,7 +86,8 @@ impl fmt::Debug for FrameBufferManager {
 }
 
 impl FrameBufferManager {
-    pub fn new(b: &Framebuffer<'_>) -> Self {
+    /// `fb` is where the framebuffer described by `b` is mapped.
+    pub fn new(b: &Framebuffer<'_>, fb: NonNull<u8>) -> Self {
         let scale_factor = 8;
         let horiz_res = b.width() as usize;
         let horiz_chars = horiz_res / FONT_WIDTH as usize / scale_factor;
@@ -93,7 +100,7 @@ impl FrameBufferManager {
         let bytes_per_pixel = (b.bpp() / 8) as usize;
         let stride = b.pitch() as usize;
 
-        let fb = unsafe { slice::from_raw_parts_mut(b.addr(), b.height() as usize * stride) };
+        let fb = unsafe { slice::from_raw_parts_mut(fb.as_ptr(), b.height() as usize * stride) };
 
         Self {
             fb,
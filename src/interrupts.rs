@@ -1,30 +1,43 @@
-use core::ops::{Index, IndexMut};
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
 
 use lazy_static::lazy_static;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
-use x86_64::structures::idt::{
-    Entry, HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
-};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use x86_64::structures::tss::TaskStateSegment;
 
-use crate::sprintln;
+use self::manager::{Eoi, register_handler};
 use crate::time::lapic;
-use crate::utils::hlt_loop;
+
+pub mod controller;
+mod exceptions;
+pub mod ioapic;
+pub mod manager;
+mod mce;
+pub mod nmi;
+pub mod stats;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Returns the top of a new stack for the interrupt stack table.
+fn ist_stack(name: &'static str) -> VirtAddr {
+    const STACK_SIZE: u64 = 4096 * 5;
+    let stack =
+        crate::mem::stack::allocate(name, STACK_SIZE).expect("failed to allocate an IST stack");
+    VirtAddr::new(stack.top)
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-
-            stack_start + STACK_SIZE as u64
-        };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack("double fault");
+        // NMIs and machine checks can arrive at any point, including right
+        // after a `syscall` or while the current stack is in a bad state.
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack("nmi");
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack("machine check");
         tss
     };
 }
@@ -50,14 +63,8 @@ struct Selectors {
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        let options = idt.double_fault.set_handler_fn(double_fault_handler);
-        unsafe {
-            options.set_stack_index(DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::ScratchTimer].set_handler_fn(scratch_timer_interrupt_handler);
+        exceptions::install(&mut idt);
+        manager::install(&mut idt);
         idt
     };
 }
@@ -75,40 +82,45 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
     IDT.load();
+    mce::init();
+
+    manager::init();
+    register_handler(
+        InterruptIndex::Timer.as_u8(),
+        "timer",
+        Eoi::Send,
+        timer_interrupt_handler,
+    );
+    register_handler(
+        InterruptIndex::ScratchTimer.as_u8(),
+        "scratch timer",
+        Eoi::Send,
+        scratch_timer_interrupt_handler,
+    );
+    // spurious interrupts must not be acknowledged.
+    register_handler(
+        InterruptIndex::Spurious.as_u8(),
+        "spurious",
+        Eoi::Skip,
+        spurious_interrupt_handler,
+    );
+    register_handler(
+        InterruptIndex::LapicError.as_u8(),
+        "lapic error",
+        Eoi::Send,
+        lapic_error_handler,
+    );
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    crate::sprintln!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    sprintln!("EXCEPTION: PAGE FAULT");
-    sprintln!("Accessed Address: {:?}", Cr2::read());
-    sprintln!("Error Code: {:?}", error_code);
-    sprintln!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn timer_interrupt_handler(_stack_frame: &InterruptStackFrame) {
     use core::num::Wrapping;
     unsafe {
         let timer = &raw mut crate::time::TIMER;
         let time = timer.read_volatile();
         timer.write_volatile(time + Wrapping(1));
-        lapic().end_of_interrupt();
     }
+    crate::time::clockevent::timer_interrupt();
+    crate::time::wheel::run();
 }
 
 /// The scratch timer is used when calibrating two clocks that both use IRQs
@@ -116,10 +128,40 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 /// It is only available to the bootstrap processor.
 pub(super) static mut SCRATCH_TIMER: usize = 0;
 
-extern "x86-interrupt" fn scratch_timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn scratch_timer_interrupt_handler(_stack_frame: &InterruptStackFrame) {
     unsafe {
         SCRATCH_TIMER = SCRATCH_TIMER.wrapping_add(1);
-        lapic().end_of_interrupt();
+    }
+}
+
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+fn spurious_interrupt_handler(_stack_frame: &InterruptStackFrame) {
+    let count = SPURIOUS_COUNT.fetch_add(1, Relaxed) + 1;
+    // don't flood the log if there are lots of them.
+    if count.is_power_of_two() {
+        log::warn!("spurious interrupt (#{count})");
+    }
+}
+
+fn lapic_error_handler(_stack_frame: &InterruptStackFrame) {
+    const ERRORS: [&str; 8] = [
+        "send checksum error",
+        "receive checksum error",
+        "send accept error",
+        "receive accept error",
+        "redirectable IPI",
+        "send illegal vector",
+        "received illegal vector",
+        "illegal register address",
+    ];
+
+    let esr = unsafe { lapic().error_status() };
+    log::error!("LAPIC error, ESR = {esr:#x}");
+    for (bit, error) in ERRORS.iter().enumerate() {
+        if esr & (1 << bit) != 0 {
+            log::error!("  {error}");
+        }
     }
 }
 
@@ -130,7 +172,12 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 #[derive(Clone, Copy)]
 pub enum InterruptIndex {
     Timer = 32,
-    ScratchTimer = 33,
+    /// Only fired by the LAPIC timer, so it stays clear of the vectors the
+    /// PICs deliver ISA IRQs at.
+    ScratchTimer = 0xFD,
+    LapicError = 0xFE,
+    /// The low four bits must be set on some older processors.
+    Spurious = 0xFF,
 }
 
 impl InterruptIndex {
@@ -139,18 +186,3 @@ impl InterruptIndex {
         *self as u8
     }
 }
-
-impl Index<InterruptIndex> for InterruptDescriptorTable {
-    type Output = Entry<HandlerFunc>;
-    #[inline]
-    fn index(&self, index: InterruptIndex) -> &Self::Output {
-        &self[index.as_u8()]
-    }
-}
-
-impl IndexMut<InterruptIndex> for InterruptDescriptorTable {
-    #[inline]
-    fn index_mut(&mut self, index: InterruptIndex) -> &mut Self::Output {
-        &mut self[index.as_u8()]
-    }
-}
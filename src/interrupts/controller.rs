@@ -0,0 +1,171 @@
+//! The interrupt controller.
+//!
+//! Normally this is the local APIC together with the I/O APICs. If ACPI
+//! doesn't report an APIC (old machines, or QEMU with `-machine ...,apic=off`)
+//! we fall back to the legacy 8259 PICs, where ISA IRQ `n` always arrives at
+//! vector `PIC_1_OFFSET + n`.
+use alloc::alloc::Global;
+
+use acpi::{InterruptModel, PlatformInfo};
+use pic8259::ChainedPics;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::manager::{self, Eoi};
+use super::{PIC_1_OFFSET, PIC_2_OFFSET, ioapic};
+use crate::time::{self, lapic};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Controller {
+    /// Local APIC and I/O APICs.
+    Apic,
+    /// Two chained 8259 PICs.
+    Pic,
+}
+
+static CONTROLLER: Once<Controller> = Once::new();
+
+static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// The IRQ on the primary PIC the secondary one is connected to.
+const CASCADE_IRQ: u8 = 2;
+
+/// The controller in use.
+pub fn controller() -> Controller {
+    *CONTROLLER
+        .get()
+        .expect("interrupt controller not initialized")
+}
+
+/// Set up whichever interrupt controller the platform has.
+///
+/// All interrupt sources are masked afterwards.
+pub fn init(platform_info: &PlatformInfo<'_, Global>) {
+    let mut pics = PICS.lock();
+    // remap the PICs even if we don't use them, so that spurious interrupts
+    // from them don't look like exceptions.
+    unsafe { pics.initialize() };
+
+    let controller = match platform_info.interrupt_model {
+        InterruptModel::Apic(_) => {
+            unsafe { pics.disable() };
+            drop(pics);
+            time::init_lapic(platform_info);
+            ioapic::init(platform_info);
+            Controller::Apic
+        }
+        _ => {
+            log::warn!("no APIC found, falling back to the 8259 PICs");
+            // mask everything except for the cascade.
+            unsafe { pics.write_masks(!(1 << CASCADE_IRQ), 0xFF) };
+            Controller::Pic
+        }
+    };
+
+    CONTROLLER.call_once(|| controller);
+}
+
+/// Read the in-service registers of both PICs, IRQ `n` is bit `n`.
+fn read_pic_isr() -> u16 {
+    const READ_ISR: u8 = 0x0B;
+    let mut primary = Port::<u8>::new(0x20);
+    let mut secondary = Port::<u8>::new(0xA0);
+    unsafe {
+        primary.write(READ_ISR);
+        secondary.write(READ_ISR);
+        (secondary.read() as u16) << 8 | primary.read() as u16
+    }
+}
+
+/// Handle `vector` if it is a spurious IRQ from the PICs, returns whether it
+/// was one.
+///
+/// When an IRQ goes away before the CPU acknowledges it, a PIC raises its
+/// lowest priority IRQ (7) instead, without marking it in service. It must
+/// not get an EOI, which would acknowledge whatever IRQ really is in service.
+/// If the secondary PIC does this, the primary one still saw a real IRQ on
+/// the cascade, so only the primary one gets an EOI.
+///
+/// Even masked PICs do this, so it applies with the APIC as well.
+pub fn handle_spurious_irq(vector: u8) -> bool {
+    if vector != PIC_1_OFFSET + 7 && vector != PIC_2_OFFSET + 7 {
+        return false;
+    }
+
+    let mut pics = PICS.lock();
+    let irq = vector - PIC_1_OFFSET;
+    if read_pic_isr() & (1 << irq) != 0 {
+        return false;
+    }
+    if irq == 15 {
+        unsafe { pics.notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ) };
+    }
+    true
+}
+
+/// Signal the end of the interrupt on `vector`.
+pub fn end_of_interrupt(vector: u8) {
+    match controller() {
+        Controller::Apic => unsafe { lapic().end_of_interrupt() },
+        Controller::Pic => unsafe { PICS.lock().notify_end_of_interrupt(vector) },
+    }
+}
+
+/// Deliver ISA IRQ `irq` to `handler`, returns the vector it arrives at.
+///
+/// With the APIC a free vector is allocated, with the PICs the vector is
+/// fixed. Returns `None` if there is no vector available.
+pub fn enable_isa_irq<F>(irq: u8, name: &'static str, handler: F) -> Option<u8>
+where
+    F: Fn(&InterruptStackFrame) + Send + Sync + 'static,
+{
+    match controller() {
+        Controller::Apic => {
+            let vector = manager::allocate_handler(name, Eoi::Send, handler)?;
+            ioapic::route_isa_irq(irq, vector, unsafe { lapic().id() });
+            Some(vector)
+        }
+        Controller::Pic => {
+            let vector = PIC_1_OFFSET + irq;
+            if !manager::claim_vector(vector) {
+                return None;
+            }
+            manager::register_handler(vector, name, Eoi::Send, handler);
+            unmask_isa_irq(irq);
+            Some(vector)
+        }
+    }
+}
+
+fn set_pic_masked(irq: u8, masked: bool) {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let (pic, bit) = ((irq / 8) as usize, irq % 8);
+        if masked {
+            masks[pic] |= 1 << bit;
+        } else {
+            masks[pic] &= !(1 << bit);
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
+// nothing masks ISA IRQs again yet.
+#[allow(dead_code)]
+pub fn mask_isa_irq(irq: u8) {
+    match controller() {
+        Controller::Apic => ioapic::mask_isa_irq(irq),
+        Controller::Pic => set_pic_masked(irq, true),
+    }
+}
+
+pub fn unmask_isa_irq(irq: u8) {
+    match controller() {
+        Controller::Apic => ioapic::unmask_isa_irq(irq),
+        Controller::Pic => set_pic_masked(irq, false),
+    }
+}
@@ -0,0 +1,388 @@
+//! CPU exception handling.
+//!
+//! Every architectural exception enters through a naked stub that pushes a
+//! dummy error code (if the CPU didn't push one) and the vector number, then
+//! jumps to a common stub that saves all general purpose registers. The
+//! dispatcher thus always gets the complete register state of the
+//! interrupted code, which we dump before giving up.
+use core::arch::naked_asm;
+use core::fmt;
+
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode};
+
+use super::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX, mce, nmi};
+use crate::mem::stack;
+use crate::sprintln;
+
+/// The state saved by the exception entry stubs.
+///
+/// The general purpose registers are pushed by `exception_common`, the
+/// vector by the per-exception stub, and everything from `error_code`
+/// onwards by the CPU (the per-exception stub pushes a zero error code for
+/// exceptions that don't have one).
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP={:#018x} CS={:#06x} RFLAGS={:#018x}",
+            self.rip, self.cs, self.rflags
+        )?;
+        writeln!(f, "RSP={:#018x} SS={:#06x}", self.rsp, self.ss)?;
+        writeln!(
+            f,
+            "RAX={:#018x} RBX={:#018x} RCX={:#018x} RDX={:#018x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:#018x} RDI={:#018x} RBP={:#018x} R8 ={:#018x}",
+            self.rsi, self.rdi, self.rbp, self.r8
+        )?;
+        writeln!(
+            f,
+            "R9 ={:#018x} R10={:#018x} R11={:#018x} R12={:#018x}",
+            self.r9, self.r10, self.r11, self.r12
+        )?;
+        writeln!(
+            f,
+            "R13={:#018x} R14={:#018x} R15={:#018x}",
+            self.r13, self.r14, self.r15
+        )?;
+        write!(
+            f,
+            "CR0={:#018x} CR2={:#018x} CR3={:#018x} CR4={:#018x}",
+            Cr0::read_raw(),
+            Cr2::read_raw(),
+            Cr3::read_raw().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )
+    }
+}
+
+// Architectural exception vectors.
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE_EXCEEDED: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const COPROCESSOR_SEGMENT_OVERRUN: u8 = 9;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const CONTROL_PROTECTION: u8 = 21;
+pub const HV_INJECTION: u8 = 28;
+pub const VMM_COMMUNICATION: u8 = 29;
+pub const SECURITY_EXCEPTION: u8 = 30;
+
+/// Returns the mnemonic and the name of an exception vector.
+pub fn exception_name(vector: u8) -> (&'static str, &'static str) {
+    match vector {
+        DIVIDE_ERROR => ("#DE", "DIVIDE ERROR"),
+        DEBUG => ("#DB", "DEBUG"),
+        NON_MASKABLE_INTERRUPT => ("NMI", "NON-MASKABLE INTERRUPT"),
+        BREAKPOINT => ("#BP", "BREAKPOINT"),
+        OVERFLOW => ("#OF", "OVERFLOW"),
+        BOUND_RANGE_EXCEEDED => ("#BR", "BOUND RANGE EXCEEDED"),
+        INVALID_OPCODE => ("#UD", "INVALID OPCODE"),
+        DEVICE_NOT_AVAILABLE => ("#NM", "DEVICE NOT AVAILABLE"),
+        DOUBLE_FAULT => ("#DF", "DOUBLE FAULT"),
+        COPROCESSOR_SEGMENT_OVERRUN => ("CSO", "COPROCESSOR SEGMENT OVERRUN"),
+        INVALID_TSS => ("#TS", "INVALID TSS"),
+        SEGMENT_NOT_PRESENT => ("#NP", "SEGMENT NOT PRESENT"),
+        STACK_SEGMENT_FAULT => ("#SS", "STACK SEGMENT FAULT"),
+        GENERAL_PROTECTION_FAULT => ("#GP", "GENERAL PROTECTION FAULT"),
+        PAGE_FAULT => ("#PF", "PAGE FAULT"),
+        X87_FLOATING_POINT => ("#MF", "X87 FLOATING POINT"),
+        ALIGNMENT_CHECK => ("#AC", "ALIGNMENT CHECK"),
+        MACHINE_CHECK => ("#MC", "MACHINE CHECK"),
+        SIMD_FLOATING_POINT => ("#XM", "SIMD FLOATING POINT"),
+        VIRTUALIZATION => ("#VE", "VIRTUALIZATION"),
+        CONTROL_PROTECTION => ("#CP", "CONTROL PROTECTION"),
+        HV_INJECTION => ("#HV", "HYPERVISOR INJECTION"),
+        VMM_COMMUNICATION => ("#VC", "VMM COMMUNICATION"),
+        SECURITY_EXCEPTION => ("#SX", "SECURITY EXCEPTION"),
+        _ => ("??", "RESERVED"),
+    }
+}
+
+/// Print the decoded error code of an exception, if it has one.
+fn report_error_code(frame: &ExceptionFrame) {
+    let error_code = frame.error_code;
+    match frame.vector as u8 {
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            let selector = SelectorErrorCode::new_truncate(error_code);
+            if selector.is_null() {
+                sprintln!("Error Code: 0 (not segment related)");
+            } else {
+                sprintln!("Error Code: {error_code:#x} {selector:?}");
+            }
+        }
+        PAGE_FAULT => {
+            sprintln!("Accessed Address: {:#018x}", Cr2::read_raw());
+            sprintln!(
+                "Error Code: {error_code:#x} {:?}",
+                PageFaultErrorCode::from_bits_truncate(error_code)
+            );
+        }
+        CONTROL_PROTECTION => {
+            let kind = match error_code & 0x7fff {
+                1 => "NEAR-RET",
+                2 => "FAR-RET/IRET",
+                3 => "ENDBRANCH",
+                4 => "RSTORSSP",
+                5 => "SETSSBSY",
+                _ => "unknown",
+            };
+            let encl = if error_code & (1 << 15) != 0 {
+                " (in enclave)"
+            } else {
+                ""
+            };
+            sprintln!("Error Code: {error_code:#x} {kind}{encl}");
+        }
+        DOUBLE_FAULT | ALIGNMENT_CHECK | VMM_COMMUNICATION | SECURITY_EXCEPTION => {
+            sprintln!("Error Code: {error_code:#x}");
+        }
+        _ => {}
+    }
+}
+
+/// Print a structured report of an exception to serial.
+pub fn report(frame: &ExceptionFrame) {
+    let (mnemonic, name) = exception_name(frame.vector as u8);
+    sprintln!("EXCEPTION: {name} ({mnemonic}, vector {})", frame.vector);
+    report_error_code(frame);
+    sprintln!("{frame}");
+}
+
+/// Called by `exception_common` with the saved register state.
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector as u8 {
+        // traps that we can simply return from.
+        BREAKPOINT | DEBUG | OVERFLOW => report(frame),
+        NON_MASKABLE_INTERRUPT => nmi::handle(frame),
+        MACHINE_CHECK => mce::handle(frame),
+        PAGE_FAULT | DOUBLE_FAULT => {
+            report(frame);
+            // overflowing into a guard page usually ends up as a double
+            // fault, since the page fault can't be delivered on that stack.
+            let overflowed =
+                stack::guard_hit(Cr2::read_raw()).or_else(|| stack::guard_hit(frame.rsp));
+            match overflowed {
+                Some(stack) => panic!(
+                    "stack overflow on the {} stack ({} KiB at {:#x}..{:#x})",
+                    stack.name,
+                    stack.size() / 1024,
+                    stack.bottom,
+                    stack.top
+                ),
+                None => panic!(
+                    "unhandled exception {}",
+                    exception_name(frame.vector as u8).0
+                ),
+            }
+        }
+        vector => {
+            report(frame);
+            panic!("unhandled exception {}", exception_name(vector).0);
+        }
+    }
+}
+
+/// Saves the general purpose registers on top of the vector and error code
+/// pushed by the per-exception stub, calls `exception_dispatch` and restores
+/// everything on return.
+///
+/// The CPU aligns the stack to 16 bytes before pushing its frame in long
+/// mode. Together with the error code, the vector and the 15 registers the
+/// stack is 16-byte aligned again at the `call`.
+#[unsafe(naked)]
+unsafe extern "C" fn exception_common() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // drop the vector and the error code.
+        "add rsp, 16",
+        "iretq",
+        dispatch = sym exception_dispatch,
+    )
+}
+
+macro_rules! exception_stubs {
+    ($($name:ident = $vector:expr $(, $error_code:ident)?;)*) => {
+        $(
+            #[unsafe(naked)]
+            unsafe extern "C" fn $name() {
+                naked_asm!(
+                    exception_stubs!(@error_code $($error_code)?),
+                    "push {vector}",
+                    "jmp {common}",
+                    vector = const $vector,
+                    common = sym exception_common,
+                )
+            }
+        )*
+    };
+    // the CPU pushed an error code for us.
+    (@error_code error_code) => { "" };
+    // keep the frame layout uniform.
+    (@error_code) => { "push 0" };
+}
+
+exception_stubs! {
+    divide_error_stub = DIVIDE_ERROR;
+    debug_stub = DEBUG;
+    nmi_stub = NON_MASKABLE_INTERRUPT;
+    breakpoint_stub = BREAKPOINT;
+    overflow_stub = OVERFLOW;
+    bound_range_exceeded_stub = BOUND_RANGE_EXCEEDED;
+    invalid_opcode_stub = INVALID_OPCODE;
+    device_not_available_stub = DEVICE_NOT_AVAILABLE;
+    double_fault_stub = DOUBLE_FAULT, error_code;
+    coprocessor_segment_overrun_stub = COPROCESSOR_SEGMENT_OVERRUN;
+    invalid_tss_stub = INVALID_TSS, error_code;
+    segment_not_present_stub = SEGMENT_NOT_PRESENT, error_code;
+    stack_segment_fault_stub = STACK_SEGMENT_FAULT, error_code;
+    general_protection_fault_stub = GENERAL_PROTECTION_FAULT, error_code;
+    page_fault_stub = PAGE_FAULT, error_code;
+    x87_floating_point_stub = X87_FLOATING_POINT;
+    alignment_check_stub = ALIGNMENT_CHECK, error_code;
+    machine_check_stub = MACHINE_CHECK;
+    simd_floating_point_stub = SIMD_FLOATING_POINT;
+    virtualization_stub = VIRTUALIZATION;
+    control_protection_stub = CONTROL_PROTECTION, error_code;
+    hv_injection_stub = HV_INJECTION;
+    vmm_communication_stub = VMM_COMMUNICATION, error_code;
+    security_exception_stub = SECURITY_EXCEPTION, error_code;
+}
+
+#[inline]
+fn stub_addr(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Point every architectural exception at its entry stub.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // SAFETY: the stubs are valid exception handlers that preserve all
+    // registers and return with `iretq`.
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(stub_addr(divide_error_stub));
+        idt.debug.set_handler_addr(stub_addr(debug_stub));
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub_addr(nmi_stub))
+            .set_stack_index(NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(stub_addr(breakpoint_stub));
+        idt.overflow.set_handler_addr(stub_addr(overflow_stub));
+        idt.bound_range_exceeded
+            .set_handler_addr(stub_addr(bound_range_exceeded_stub));
+        idt.invalid_opcode
+            .set_handler_addr(stub_addr(invalid_opcode_stub));
+        idt.device_not_available
+            .set_handler_addr(stub_addr(device_not_available_stub));
+        idt.double_fault
+            .set_handler_addr(stub_addr(double_fault_stub))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt[COPROCESSOR_SEGMENT_OVERRUN]
+            .set_handler_addr(stub_addr(coprocessor_segment_overrun_stub));
+        idt.invalid_tss
+            .set_handler_addr(stub_addr(invalid_tss_stub));
+        idt.segment_not_present
+            .set_handler_addr(stub_addr(segment_not_present_stub));
+        idt.stack_segment_fault
+            .set_handler_addr(stub_addr(stack_segment_fault_stub));
+        idt.general_protection_fault
+            .set_handler_addr(stub_addr(general_protection_fault_stub));
+        idt.page_fault.set_handler_addr(stub_addr(page_fault_stub));
+        idt.x87_floating_point
+            .set_handler_addr(stub_addr(x87_floating_point_stub));
+        idt.alignment_check
+            .set_handler_addr(stub_addr(alignment_check_stub));
+        idt.machine_check
+            .set_handler_addr(stub_addr(machine_check_stub))
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_addr(stub_addr(simd_floating_point_stub));
+        idt.virtualization
+            .set_handler_addr(stub_addr(virtualization_stub));
+        idt.cp_protection_exception
+            .set_handler_addr(stub_addr(control_protection_stub));
+        idt.hv_injection_exception
+            .set_handler_addr(stub_addr(hv_injection_stub));
+        idt.vmm_communication_exception
+            .set_handler_addr(stub_addr(vmm_communication_stub));
+        idt.security_exception
+            .set_handler_addr(stub_addr(security_exception_stub));
+    }
+}
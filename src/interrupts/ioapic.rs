@@ -0,0 +1,207 @@
+//! Routing interrupts through the I/O APICs.
+//!
+//! ISA IRQs are identity mapped to global system interrupts (GSIs), unless
+//! the MADT has an interrupt source override for them, which may also change
+//! the polarity and trigger mode. Each GSI belongs to exactly one I/O APIC.
+use alloc::alloc::Global;
+use alloc::vec::Vec;
+
+use acpi::platform::interrupt::{Polarity, TriggerMode};
+use acpi::{InterruptModel, PlatformInfo};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::mem::mmio::{self, CacheMode};
+use crate::time::{APIC_MASKED, IOAPICVER, IoApic};
+
+/// Redirection entry: the interrupt input pin is active low.
+const REDIR_ACTIVE_LOW: u32 = 1 << 13;
+/// Redirection entry: the interrupt is level triggered.
+const REDIR_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+struct IoApicEntry {
+    ioapic: IoApic,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+/// How an ISA IRQ is wired to a GSI.
+#[derive(Clone, Copy, Debug)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+struct Routing {
+    ioapics: Vec<IoApicEntry>,
+    /// Indexed by ISA IRQ.
+    isa_routes: [IsaRoute; 16],
+}
+
+static ROUTING: Mutex<Option<Routing>> = Mutex::new(None);
+
+/// Find all I/O APICs and interrupt source overrides from the MADT and mask
+/// every redirection entry.
+pub fn init(platform_info: &PlatformInfo<'_, Global>) {
+    let apic = match &platform_info.interrupt_model {
+        InterruptModel::Apic(apic) => apic,
+        _ => panic!("unknown interrupt model"),
+    };
+
+    let mut ioapics = Vec::new();
+    for io_apic in apic.io_apics.iter() {
+        let start_ptr = mmio::map(
+            "ioapic",
+            io_apic.address as u64,
+            0x20,
+            CacheMode::Uncacheable,
+        )
+        .expect("failed to map an I/O APIC");
+        let mut ioapic = IoApic { start_ptr };
+
+        let ioapicver = unsafe { ioapic.read_register(IOAPICVER) };
+
+        // https://wiki.osdev.org/IOAPIC#IOAPICVER
+        let redirection_entries = ((ioapicver >> 16) & 0xFF) + 1;
+
+        // Per https://wiki.osdev.org/APIC#IO_APIC_Registers, set the "masked" flag
+        // of all redir entries until someone routes them.
+        for idx in 0..redirection_entries {
+            unsafe { ioapic.update_register(redirection_register(idx), |v| v | APIC_MASKED) }
+        }
+
+        ioapics.push(IoApicEntry {
+            ioapic,
+            gsi_base: io_apic.global_system_interrupt_base,
+            redirection_entries,
+        });
+    }
+
+    // ISA interrupts are active high and edge triggered.
+    let mut isa_routes: [IsaRoute; 16] = core::array::from_fn(|irq| IsaRoute {
+        gsi: irq as u32,
+        active_low: false,
+        level_triggered: false,
+    });
+    for ov in apic.interrupt_source_overrides.iter() {
+        let Some(route) = isa_routes.get_mut(ov.isa_source as usize) else {
+            continue;
+        };
+        route.gsi = ov.global_system_interrupt;
+        route.active_low = match ov.polarity {
+            Polarity::SameAsBus | Polarity::ActiveHigh => false,
+            Polarity::ActiveLow => true,
+        };
+        route.level_triggered = match ov.trigger_mode {
+            TriggerMode::SameAsBus | TriggerMode::Edge => false,
+            TriggerMode::Level => true,
+        };
+    }
+
+    without_interrupts(|| {
+        *ROUTING.lock() = Some(Routing {
+            ioapics,
+            isa_routes,
+        })
+    });
+}
+
+/// https://wiki.osdev.org/IOAPIC#IOREDTBL
+#[inline]
+fn redirection_register(idx: u32) -> u8 {
+    (0x10 + idx * 2) as u8
+}
+
+fn with_routing<R>(f: impl FnOnce(&mut Routing) -> R) -> R {
+    without_interrupts(|| f(ROUTING.lock().as_mut().expect("I/O APICs not initialized")))
+}
+
+impl Routing {
+    /// Find the I/O APIC handling `gsi` and the low register of its
+    /// redirection entry.
+    fn entry(&mut self, gsi: u32) -> (&mut IoApic, u8) {
+        let entry = self
+            .ioapics
+            .iter_mut()
+            .find(|e| (e.gsi_base..e.gsi_base + e.redirection_entries).contains(&gsi))
+            .unwrap_or_else(|| panic!("no I/O APIC handles GSI {gsi}"));
+        let reg = redirection_register(gsi - entry.gsi_base);
+        (&mut entry.ioapic, reg)
+    }
+}
+
+/// How `irq` is wired, taking interrupt source overrides into account.
+pub fn isa_route(irq: u8) -> IsaRoute {
+    with_routing(|routing| routing.isa_routes[irq as usize])
+}
+
+/// Deliver `gsi` to `vector` on the processor with the LAPIC ID `cpu`.
+///
+/// The entry is unmasked afterwards.
+pub fn route_gsi(gsi: u32, vector: u8, cpu: u32, active_low: bool, level_triggered: bool) {
+    // fixed delivery mode, physical destination mode.
+    let mut low = vector as u32;
+    if active_low {
+        low |= REDIR_ACTIVE_LOW;
+    }
+    if level_triggered {
+        low |= REDIR_LEVEL_TRIGGERED;
+    }
+
+    with_routing(|routing| {
+        let (ioapic, reg) = routing.entry(gsi);
+        unsafe {
+            // set the destination first, the entry is still masked.
+            ioapic.write_register(reg, low | APIC_MASKED);
+            ioapic.write_register(reg + 1, cpu << (56 - 32));
+            ioapic.write_register(reg, low);
+        }
+    });
+}
+
+/// Deliver the ISA interrupt `irq` to `vector` on the processor with the
+/// LAPIC ID `cpu`, honoring the polarity and trigger mode overrides.
+///
+/// The entry is unmasked afterwards.
+pub fn route_isa_irq(irq: u8, vector: u8, cpu: u32) {
+    let route = isa_route(irq);
+    route_gsi(
+        route.gsi,
+        vector,
+        cpu,
+        route.active_low,
+        route.level_triggered,
+    );
+}
+
+fn set_gsi_masked(gsi: u32, masked: bool) {
+    with_routing(|routing| {
+        let (ioapic, reg) = routing.entry(gsi);
+        unsafe {
+            ioapic.update_register(reg, |v| {
+                if masked {
+                    v | APIC_MASKED
+                } else {
+                    v & !APIC_MASKED
+                }
+            })
+        }
+    });
+}
+
+pub fn mask_gsi(gsi: u32) {
+    set_gsi_masked(gsi, true);
+}
+
+pub fn unmask_gsi(gsi: u32) {
+    set_gsi_masked(gsi, false);
+}
+
+pub fn mask_isa_irq(irq: u8) {
+    mask_gsi(isa_route(irq).gsi);
+}
+
+pub fn unmask_isa_irq(irq: u8) {
+    unmask_gsi(isa_route(irq).gsi);
+}
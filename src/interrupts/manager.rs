@@ -0,0 +1,153 @@
+//! Interrupt vector allocation and dispatch.
+//!
+//! Every vector from 32 upwards points at the same dispatcher, which looks up
+//! the handler registered for the vector at runtime, runs it and then sends
+//! the end of interrupt, so that handlers don't have to.
+use alloc::boxed::Box;
+use core::ops::RangeInclusive;
+
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::set_general_handler;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use super::{InterruptIndex, controller, stats};
+use crate::sprintln;
+use crate::utils::rdtsc;
+
+/// Vectors handed out by `allocate_vector`.
+///
+/// Everything below is either an exception, one of the `InterruptIndex`
+/// vectors or where the legacy PICs deliver their IRQs (see `controller`).
+/// Everything above is left for the local APIC's own vectors.
+pub const DYNAMIC_VECTORS: RangeInclusive<u8> = 48..=0xEF;
+
+/// An interrupt handler. Closures can carry whatever context the driver needs.
+pub type Handler = Box<dyn Fn(&InterruptStackFrame) + Send + Sync>;
+
+/// Whether the dispatcher should signal the end of interrupt after running
+/// the handler.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Eoi {
+    Send,
+    /// For interrupts that must not be acknowledged, such as spurious ones.
+    Skip,
+}
+
+struct Registration {
+    handler: Handler,
+    /// Shown in the interrupt statistics.
+    name: &'static str,
+    eoi: Eoi,
+}
+
+/// Registered handlers for vectors 32..=255.
+///
+/// Writers must disable interrupts while holding the lock, otherwise the
+/// dispatcher could spin on it forever.
+static HANDLERS: [RwLock<Option<Registration>>; 224] = [const { RwLock::new(None) }; 224];
+
+/// One bit per vector, set if the vector is in use.
+static ALLOCATED: Mutex<[u64; 4]> = Mutex::new([0; 4]);
+
+#[inline]
+fn slot(vector: u8) -> &'static RwLock<Option<Registration>> {
+    assert!(vector >= 32, "vector {vector} is an exception");
+    &HANDLERS[vector as usize - 32]
+}
+
+/// Point every non-exception vector at the dispatcher.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    set_general_handler!(idt, dispatch, 32..=255);
+}
+
+/// Mark the fixed vectors as allocated so that `allocate_vector` never
+/// returns them.
+pub fn init() {
+    for index in [
+        InterruptIndex::Timer,
+        InterruptIndex::ScratchTimer,
+        InterruptIndex::LapicError,
+        InterruptIndex::Spurious,
+    ] {
+        claim_vector(index.as_u8());
+    }
+}
+
+fn dispatch(frame: InterruptStackFrame, vector: u8, _error_code: Option<u64>) {
+    let start = rdtsc();
+
+    // the PICs' spurious IRQs arrive at the vectors of real ones.
+    if controller::handle_spurious_irq(vector) {
+        stats::record(vector, rdtsc().wrapping_sub(start));
+        return;
+    }
+
+    let eoi = match &*slot(vector).read() {
+        Some(registration) => {
+            (registration.handler)(&frame);
+            registration.eoi
+        }
+        None => {
+            sprintln!("unhandled interrupt on vector {vector}");
+            Eoi::Send
+        }
+    };
+
+    stats::record(vector, rdtsc().wrapping_sub(start));
+
+    if eoi == Eoi::Send {
+        controller::end_of_interrupt(vector);
+    }
+}
+
+/// Mark `vector` as in use, returns `false` if it already was.
+pub fn claim_vector(vector: u8) -> bool {
+    without_interrupts(|| {
+        let mut allocated = ALLOCATED.lock();
+        let (word, bit) = (vector as usize / 64, vector % 64);
+        let free = allocated[word] & (1 << bit) == 0;
+        allocated[word] |= 1 << bit;
+        free
+    })
+}
+
+/// Find a free vector in `DYNAMIC_VECTORS` and mark it as in use.
+pub fn allocate_vector() -> Option<u8> {
+    DYNAMIC_VECTORS
+        .into_iter()
+        .find(|&vector| claim_vector(vector))
+}
+
+/// Register the handler that runs when `vector` fires.
+///
+/// Panics if the vector already has a handler.
+pub fn register_handler<F>(vector: u8, name: &'static str, eoi: Eoi, handler: F)
+where
+    F: Fn(&InterruptStackFrame) + Send + Sync + 'static,
+{
+    let handler = Box::new(handler);
+    without_interrupts(|| {
+        let mut slot = slot(vector).write();
+        assert!(slot.is_none(), "vector {vector} already has a handler");
+        *slot = Some(Registration { handler, name, eoi });
+    });
+}
+
+/// Allocate a vector and register `handler` for it.
+pub fn allocate_handler<F>(name: &'static str, eoi: Eoi, handler: F) -> Option<u8>
+where
+    F: Fn(&InterruptStackFrame) + Send + Sync + 'static,
+{
+    let vector = allocate_vector()?;
+    register_handler(vector, name, eoi, handler);
+    Some(vector)
+}
+
+/// The name `vector`'s handler was registered with.
+pub fn handler_name(vector: u8) -> &'static str {
+    if vector < 32 {
+        return "exception";
+    }
+    without_interrupts(|| slot(vector).read().as_ref().map_or("-", |r| r.name))
+}
@@ -0,0 +1,192 @@
+//! Machine check architecture.
+//!
+//! See Intel SDM Vol. 3B chapter 16 ("Machine-Check Architecture") and
+//! chapter 17 ("Interpreting Machine-Check Error Codes").
+use core::arch::x86_64::__cpuid;
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+use super::exceptions::{ExceptionFrame, report};
+use crate::{sprint, sprintln};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+
+/// `IA32_MCG_CAP`: the `IA32_MCG_CTL` register is present.
+const MCG_CTL_P: u64 = 1 << 8;
+
+/// `IA32_MCG_STATUS`: restart IP valid, the program can be resumed.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// `IA32_MCG_STATUS`: error IP valid, RIP points to the faulting instruction.
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+/// `IA32_MCG_STATUS`: machine check in progress.
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+// IA32_MCi_STATUS bits.
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+#[inline]
+fn mci_ctl(bank: u32) -> Msr {
+    Msr::new(0x400 + 4 * bank)
+}
+
+#[inline]
+fn mci_status(bank: u32) -> Msr {
+    Msr::new(0x401 + 4 * bank)
+}
+
+#[inline]
+fn mci_addr(bank: u32) -> Msr {
+    Msr::new(0x402 + 4 * bank)
+}
+
+#[inline]
+fn mci_misc(bank: u32) -> Msr {
+    Msr::new(0x403 + 4 * bank)
+}
+
+fn bank_count() -> u32 {
+    (unsafe { Msr::new(IA32_MCG_CAP).read() } & 0xff) as u32
+}
+
+/// Whether the CPU supports machine check exceptions and the MCA banks.
+fn supported() -> (bool, bool) {
+    let edx = __cpuid(1).edx;
+    (edx & (1 << 7) != 0, edx & (1 << 14) != 0)
+}
+
+/// Describe the architecturally defined part (bits 15:0) of an MCA error code.
+fn describe_error_code(code: u16) -> &'static str {
+    // the "filtering" bit is not part of the error type.
+    let code = code & !(1 << 12);
+
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified error",
+        0x0002 => "microcode ROM parity error",
+        0x0003 => "external error (BINIT# from another processor)",
+        0x0004 => "functional redundancy check master/slave error",
+        0x0005 => "internal parity error",
+        0x0006 => "SMM handler code access violation",
+        0x0400 => "internal timer error",
+        0x0401..=0x07ff => "internal unclassified error",
+        // compound error codes.
+        _ if code & 0xfffc == 0x000c => "generic cache hierarchy error",
+        _ if code & 0xfff0 == 0x0010 => "TLB error",
+        _ if code & 0xff80 == 0x0080 => "memory controller error",
+        _ if code & 0xff00 == 0x0100 => "cache hierarchy error",
+        _ if code & 0xf800 == 0x0800 => "bus/interconnect error",
+        _ => "unknown error",
+    }
+}
+
+fn report_bank(bank: u32, status: u64) {
+    let error_code = status as u16;
+    let model_code = (status >> 16) as u16;
+    sprintln!(
+        "MCA bank {bank}: status={status:#018x} error={error_code:#06x} ({}) model={model_code:#06x}",
+        describe_error_code(error_code)
+    );
+
+    let flags = [
+        (MCI_STATUS_OVER, " OVERFLOW"),
+        (MCI_STATUS_UC, " UNCORRECTED"),
+        (MCI_STATUS_EN, " ENABLED"),
+        (MCI_STATUS_PCC, " CONTEXT-CORRUPT"),
+    ];
+    sprint!("  flags:");
+    for (bit, name) in flags {
+        if status & bit != 0 {
+            sprint!("{name}");
+        }
+    }
+    sprintln!();
+
+    if status & MCI_STATUS_ADDRV != 0 {
+        sprintln!("  address: {:#018x}", unsafe { mci_addr(bank).read() });
+    }
+    if status & MCI_STATUS_MISCV != 0 {
+        sprintln!("  misc: {:#018x}", unsafe { mci_misc(bank).read() });
+    }
+}
+
+/// Report and clear every bank with a valid error, returns whether any of them
+/// was uncorrected or corrupted the processor context.
+fn report_banks() -> bool {
+    let mut fatal = false;
+    for bank in 0..bank_count() {
+        let status = unsafe { mci_status(bank).read() };
+        if status & MCI_STATUS_VAL == 0 {
+            continue;
+        }
+
+        report_bank(bank, status);
+        fatal |= status & (MCI_STATUS_UC | MCI_STATUS_PCC) != 0;
+
+        unsafe { mci_status(bank).write(0) }
+    }
+    fatal
+}
+
+/// Enable machine check exceptions and all error reporting banks.
+///
+/// Errors logged before the last reset (which are a common cause of a reset
+/// in the first place) are reported here.
+pub fn init() {
+    let (mce, mca) = supported();
+    if !mce {
+        sprintln!("machine check exceptions not supported");
+        return;
+    }
+
+    if mca {
+        sprintln!("MCA: {} banks", bank_count());
+        if report_banks() {
+            sprintln!("MCA: the errors above were logged before this boot");
+        }
+
+        unsafe {
+            if Msr::new(IA32_MCG_CAP).read() & MCG_CTL_P != 0 {
+                Msr::new(IA32_MCG_CTL).write(u64::MAX);
+            }
+            for bank in 0..bank_count() {
+                mci_ctl(bank).write(u64::MAX);
+                mci_status(bank).write(0);
+            }
+        }
+    }
+
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) }
+}
+
+/// Called from the exception dispatcher on the machine check IST stack.
+pub(super) fn handle(frame: &ExceptionFrame) {
+    report(frame);
+
+    let mut mcg_status = Msr::new(IA32_MCG_STATUS);
+    let status = unsafe { mcg_status.read() };
+    sprintln!(
+        "MCG_STATUS={status:#x} (RIPV={} EIPV={} MCIP={})",
+        status & MCG_STATUS_RIPV != 0,
+        status & MCG_STATUS_EIPV != 0,
+        status & MCG_STATUS_MCIP != 0
+    );
+
+    let fatal = !supported().1 || report_banks();
+
+    if fatal || status & MCG_STATUS_RIPV == 0 {
+        panic!("unrecoverable machine check");
+    }
+
+    // the error was corrected, clear MCIP so that the next machine check
+    // doesn't shut down the processor.
+    unsafe { mcg_status.write(0) }
+}
@@ -0,0 +1,101 @@
+//! Non-maskable interrupts.
+//!
+//! NMIs can arrive at any time, even while we hold a lock with interrupts
+//! disabled, so nothing in here may take a lock other than the serial port's
+//! (which we accept, since by then something has already gone wrong).
+use core::mem;
+use core::sync::atomic::Ordering::{Relaxed, SeqCst};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use super::exceptions::{ExceptionFrame, report};
+use crate::sprintln;
+use crate::time::try_lapic;
+
+/// A hook that gets the first look at every NMI, e.g. a watchdog.
+///
+/// Returns whether the NMI was meant for it.
+pub type NmiHook = fn(&ExceptionFrame) -> bool;
+
+/// The installed `NmiHook`, or 0 if there is none. Stored as an integer
+/// because a lock is out of the question in NMI context.
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Set when some CPU panicked, all CPUs receiving an NMI afterwards stop.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Number of NMIs received so far.
+pub fn count() -> u64 {
+    NMI_COUNT.load(Relaxed)
+}
+
+// nothing sends us NMIs on purpose yet.
+#[allow(dead_code)]
+pub fn set_hook(hook: Option<NmiHook>) {
+    HOOK.store(hook.map_or(0, |hook| hook as usize), SeqCst);
+}
+
+/// Stop all other CPUs by sending them an NMI.
+///
+/// Called from the panic handler so that the other CPUs don't keep running
+/// (and printing) on top of the panic message.
+pub fn panic_all_cpus() {
+    if PANICKING.swap(true, SeqCst) {
+        // someone else already did this.
+        return;
+    }
+
+    if let Some(mut lapic) = try_lapic() {
+        unsafe { lapic.send_nmi_all_excluding_self() }
+    }
+}
+
+/// Called from the exception dispatcher on the NMI IST stack.
+pub(super) fn handle(frame: &ExceptionFrame) {
+    NMI_COUNT.fetch_add(1, Relaxed);
+
+    if PANICKING.load(SeqCst) {
+        sprintln!("NMI: another CPU panicked, halting");
+        loop {
+            interrupts::disable();
+            x86_64::instructions::hlt();
+        }
+    }
+
+    let hook = HOOK.load(SeqCst);
+    if hook != 0 {
+        // SAFETY: only `set_hook` writes `HOOK`, and it only stores `NmiHook`s.
+        let hook: NmiHook = unsafe { mem::transmute::<usize, NmiHook>(hook) };
+        if hook(frame) {
+            return;
+        }
+    }
+
+    // NMIs not claimed by anyone may come from the chipset, in which case
+    // the reason is found in system control port B.
+    let port_b: u8 = unsafe { Port::new(0x61).read() };
+    let parity_error = port_b & (1 << 7) != 0;
+    let io_check = port_b & (1 << 6) != 0;
+
+    if parity_error || io_check {
+        report(frame);
+        panic!(
+            "NMI: {}{}",
+            if parity_error {
+                "memory parity error (SERR#) "
+            } else {
+                ""
+            },
+            if io_check { "I/O channel check" } else { "" }
+        );
+    }
+
+    sprintln!(
+        "NMI received for unknown reason at {:#018x}, continuing",
+        frame.rip
+    );
+}
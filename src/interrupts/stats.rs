@@ -0,0 +1,129 @@
+//! Per-vector interrupt statistics.
+//!
+//! Counts every dispatched interrupt per vector and per CPU and measures how
+//! long the handlers take using the TSC. `print_report` dumps everything in
+//! a format similar to Linux's `/proc/interrupts`.
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicU64, AtomicUsize};
+
+use super::manager::handler_name;
+use super::nmi;
+use crate::time::{Instant, try_lapic};
+use crate::{print, sprint};
+
+/// CPUs with a higher LAPIC ID are counted as the last one.
+pub const MAX_CPUS: usize = 16;
+
+/// More interrupts per second than this on a single vector is suspicious.
+pub const STORM_THRESHOLD: u64 = 10_000;
+
+/// Handlers taking longer than this many TSC cycles are reported as slow.
+pub const SLOW_HANDLER_CYCLES: u64 = 100_000;
+
+struct VectorStats {
+    counts: [AtomicU64; MAX_CPUS],
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+    /// Total count at the time of the last report.
+    last_reported: AtomicU64,
+}
+
+impl VectorStats {
+    const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; MAX_CPUS],
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+            last_reported: AtomicU64::new(0),
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.iter().map(|count| count.load(Relaxed)).sum()
+    }
+}
+
+static STATS: [VectorStats; 256] = [const { VectorStats::new() }; 256];
+
+/// Number of CPU columns to print, i.e. the highest CPU index seen plus one.
+static CPUS_SEEN: AtomicUsize = AtomicUsize::new(1);
+
+/// When the previous report was written, in nanoseconds since boot.
+static LAST_REPORT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Index of the current CPU, derived from its LAPIC ID.
+fn current_cpu() -> usize {
+    try_lapic()
+        .map_or(0, |mut lapic| unsafe { lapic.id() } as usize)
+        .min(MAX_CPUS - 1)
+}
+
+/// Record one interrupt on `vector` whose handler ran for `cycles`.
+pub(super) fn record(vector: u8, cycles: u64) {
+    let cpu = current_cpu();
+    let stats = &STATS[vector as usize];
+    stats.counts[cpu].fetch_add(1, Relaxed);
+    stats.total_cycles.fetch_add(cycles, Relaxed);
+    stats.max_cycles.fetch_max(cycles, Relaxed);
+    CPUS_SEEN.fetch_max(cpu + 1, Relaxed);
+}
+
+/// Write the report to `out`.
+///
+/// The rates are relative to the previous report.
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    let cpus = CPUS_SEEN.load(Relaxed);
+    let now = Instant::now().since_boot().as_nanos() as u64;
+    let elapsed_nanos = now.saturating_sub(LAST_REPORT_NANOS.swap(now, Relaxed));
+
+    write!(out, "vec")?;
+    for cpu in 0..cpus {
+        write!(out, " {:>7}", alloc::format!("CPU{cpu}"))?;
+    }
+    writeln!(out, " {:>6} {:>7} {:>7} name", "/s", "avg", "max")?;
+
+    for (vector, stats) in STATS.iter().enumerate() {
+        let total = stats.total();
+        if total == 0 {
+            continue;
+        }
+
+        write!(out, "{vector:>3}")?;
+        for count in &stats.counts[..cpus] {
+            write!(out, " {:>7}", count.load(Relaxed))?;
+        }
+
+        let delta = total - stats.last_reported.swap(total, Relaxed);
+        let rate = (elapsed_nanos != 0)
+            .then(|| (delta as u128 * 1_000_000_000 / elapsed_nanos as u128) as u64);
+        let max = stats.max_cycles.load(Relaxed);
+        let avg = stats.total_cycles.load(Relaxed) / total;
+        match rate {
+            Some(rate) => write!(out, " {rate:>6}")?,
+            None => write!(out, " {:>6}", "-")?,
+        }
+        write!(out, " {avg:>7} {max:>7} {}", handler_name(vector as u8))?;
+
+        if rate.is_some_and(|rate| rate > STORM_THRESHOLD) {
+            write!(out, " (storm?)")?;
+        }
+        if max > SLOW_HANDLER_CYCLES {
+            write!(out, " (slow)")?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "NMI {:>7}", nmi::count())
+}
+
+/// Print the report to serial and, if it is set up, the screen.
+pub fn print_report() {
+    let mut s = String::new();
+    report(&mut s).expect("formatting to a String failed");
+    sprint!("{s}");
+    if crate::draw::initialized() {
+        print!("{s}");
+    }
+}
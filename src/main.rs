@@ -1,4 +1,5 @@
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 #![feature(allocator_api)] // *sigh*. why does the new major version of `acpi` require this now?
 #![feature(format_args_nl)]
 #![no_std]
@@ -22,11 +23,43 @@ mod utils;
 #[no_mangle]
 pub extern "C" fn kernel_start() -> ! {
     sprintln!("im alive");
+    setup::init_memory();
+    // the bootloader's stack has nothing below it to catch an overflow.
+    let stack = mem::stack::allocate("kernel", setup::STACK_SIZE)
+        .expect("failed to allocate the kernel stack");
+    unsafe { mem::stack::switch_to(stack, kernel_main) }
+}
+
+extern "C" fn kernel_main() -> ! {
     setup::init();
     sprintln!("huh");
+    mem::report::print_report();
+    #[cfg(feature = "debug-alloc")]
+    mem::debug::dump_allocations();
+    #[cfg(feature = "debug-alloc")]
+    let mark = mem::debug::mark();
+    println!("{}", time::rtc::now());
     for _ in 0..8 {
         println!(" 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0");
         println!("0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 ");
     }
-    loop {}
+    let start = time::now();
+    delay(Duration::from_secs(1));
+    sprintln!("delay(1s) took {:?}", start.elapsed());
+    #[cfg(feature = "debug-alloc")]
+    mem::debug::dump_allocations_since(mark);
+    interrupts::stats::print_report();
+
+    // everything else happens in interrupt handlers, which leave printing
+    // reports to us.
+    loop {
+        x86_64::instructions::interrupts::disable();
+        if mem::report::take_request() {
+            x86_64::instructions::interrupts::enable();
+            mem::report::print_report();
+        } else {
+            // nothing can come in between enabling interrupts and halting.
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
 }
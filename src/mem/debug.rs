@@ -0,0 +1,251 @@
+//! A debugging wrapper around the global allocator.
+//!
+//! Only built with the `debug-alloc` feature. Every allocation gets a header
+//! and red zones on both sides:
+//!
+//! ```text
+//! | header | front red zone | data | back red zone |
+//! ```
+//!
+//! - new memory is filled with `UNINIT_BYTE`, freed memory with
+//!   `POISON_BYTE`, so reading either stands out
+//! - the red zones are checked on free, to catch overruns
+//! - the header links every live allocation into a list, together with the
+//!   return addresses of its callers, so leaks can be found with
+//!   `dump_allocations`.
+//!
+//! The callers are found by following the frame pointers, so build with
+//! `-C force-frame-pointers=yes` to get useful call sites.
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::{mem, ptr, slice};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::slab::SlabAllocator;
+use super::stack;
+use crate::{sprint, sprintln};
+
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xFD;
+const UNINIT_BYTE: u8 = 0xCD;
+const POISON_BYTE: u8 = 0xDD;
+
+/// Marks live allocations, freed ones are overwritten with poison.
+const MAGIC: u64 = 0xA110_CA7E_D0D0_CAFE;
+
+/// How many return addresses to record.
+const CALLERS: usize = 6;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    /// Number of the allocation, counting from boot.
+    id: u64,
+    layout: Layout,
+    callers: [usize; CALLERS],
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+struct Allocations {
+    head: *mut Header,
+    count: usize,
+    bytes: usize,
+    next_id: u64,
+}
+
+// SAFETY: the headers are only accessed with the lock held.
+unsafe impl Send for Allocations {}
+
+static ALLOCATIONS: Mutex<Allocations> = Mutex::new(Allocations {
+    head: ptr::null_mut(),
+    count: 0,
+    bytes: 0,
+    next_id: 0,
+});
+
+/// Offset of the data from the start of the block.
+fn data_offset(layout: &Layout) -> usize {
+    (mem::size_of::<Header>() + RED_ZONE).next_multiple_of(layout.align())
+}
+
+/// The layout of the whole block for an allocation of `layout`.
+fn block_layout(layout: &Layout) -> Layout {
+    let size = data_offset(layout) + layout.size() + RED_ZONE;
+    let align = layout.align().max(mem::align_of::<Header>());
+    Layout::from_size_align(size, align).unwrap()
+}
+
+/// Return addresses of the callers, found by following the frame pointers.
+///
+/// Only frames on one of our own stacks are followed, so a missing frame
+/// pointer can't make us read unmapped memory.
+#[inline(always)]
+fn callers() -> [usize; CALLERS] {
+    let mut callers = [0; CALLERS];
+    let mut rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    let Some(stack) = stack::containing(rbp as u64) else {
+        return callers;
+    };
+    for caller in callers.iter_mut() {
+        let in_stack = stack.bottom as usize <= rbp && rbp + 16 <= stack.top as usize;
+        if !in_stack || !rbp.is_multiple_of(8) {
+            break;
+        }
+        let frame = rbp as *const usize;
+        unsafe {
+            *caller = frame.add(1).read();
+            let next = frame.read();
+            // frames only get older upwards.
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+    }
+    callers
+}
+
+fn print_callers(callers: &[usize; CALLERS]) {
+    sprint!("  called from");
+    for caller in callers.iter().take_while(|&&caller| caller != 0) {
+        sprint!(" {caller:#x}");
+    }
+    sprintln!();
+}
+
+/// Check the red zones of the allocation of `layout` at `data`.
+unsafe fn check_red_zones(header: &Header, data: *mut u8, layout: &Layout) {
+    let front = data.sub(RED_ZONE);
+    let back = data.add(layout.size());
+    for (name, zone) in [("before", front), ("after", back)] {
+        let zone = slice::from_raw_parts(zone, RED_ZONE);
+        if let Some(i) = zone.iter().position(|&b| b != RED_ZONE_BYTE) {
+            sprintln!(
+                "heap corruption {name} allocation #{} of {} bytes at {data:p}, red zone byte {i} is {:#x}",
+                header.id,
+                layout.size(),
+                zone[i]
+            );
+            print_callers(&header.callers);
+            panic!("heap corruption");
+        }
+    }
+}
+
+pub struct DebugAllocator {
+    inner: &'static SlabAllocator,
+}
+
+impl DebugAllocator {
+    pub const fn new(inner: &'static SlabAllocator) -> Self {
+        Self { inner }
+    }
+}
+
+unsafe impl GlobalAlloc for DebugAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let callers = callers();
+        let block = self.inner.alloc(block_layout(&layout));
+        if block.is_null() {
+            return block;
+        }
+
+        let data = block.add(data_offset(&layout));
+        let header = block.cast::<Header>();
+        let front = header.add(1).cast::<u8>();
+        ptr::write_bytes(front, RED_ZONE_BYTE, data.offset_from(front) as usize);
+        ptr::write_bytes(data, UNINIT_BYTE, layout.size());
+        ptr::write_bytes(data.add(layout.size()), RED_ZONE_BYTE, RED_ZONE);
+
+        without_interrupts(|| {
+            let mut allocations = ALLOCATIONS.lock();
+            header.write(Header {
+                magic: MAGIC,
+                id: allocations.next_id,
+                layout,
+                callers,
+                prev: ptr::null_mut(),
+                next: allocations.head,
+            });
+            if let Some(next) = allocations.head.as_mut() {
+                next.prev = header;
+            }
+            allocations.head = header;
+            allocations.next_id += 1;
+            allocations.count += 1;
+            allocations.bytes += layout.size();
+        });
+        data
+    }
+
+    unsafe fn dealloc(&self, data: *mut u8, layout: Layout) {
+        let block = data.sub(data_offset(&layout));
+        let header = &mut *block.cast::<Header>();
+        if header.magic != MAGIC || header.layout != layout {
+            sprintln!(
+                "bad free of {} bytes at {data:p}: not allocated, freed twice or wrong layout",
+                layout.size()
+            );
+            print_callers(&callers());
+            panic!("bad free");
+        }
+        check_red_zones(header, data, &layout);
+
+        without_interrupts(|| {
+            let mut allocations = ALLOCATIONS.lock();
+            match header.prev.as_mut() {
+                Some(prev) => prev.next = header.next,
+                None => allocations.head = header.next,
+            }
+            if let Some(next) = header.next.as_mut() {
+                next.prev = header.prev;
+            }
+            allocations.count -= 1;
+            allocations.bytes -= layout.size();
+        });
+
+        let block_layout = block_layout(&layout);
+        ptr::write_bytes(block, POISON_BYTE, block_layout.size());
+        self.inner.dealloc(block, block_layout);
+    }
+}
+
+/// The number of the next allocation, to pass to `dump_allocations_since`.
+pub fn mark() -> u64 {
+    without_interrupts(|| ALLOCATIONS.lock().next_id)
+}
+
+/// Print every live allocation.
+pub fn dump_allocations() {
+    dump_allocations_since(0);
+}
+
+/// Print the live allocations made since `mark` returned `since`, which are
+/// leaks if whatever made them is done.
+pub fn dump_allocations_since(since: u64) {
+    without_interrupts(|| {
+        let allocations = ALLOCATIONS.lock();
+        sprintln!(
+            "{} live allocations, {} bytes",
+            allocations.count,
+            allocations.bytes
+        );
+
+        // newest first.
+        let mut header = allocations.head;
+        while let Some(h) = unsafe { header.as_ref() } {
+            if h.id < since {
+                break;
+            }
+            let data = unsafe { header.cast::<u8>().add(data_offset(&h.layout)) };
+            sprintln!("#{} {} bytes at {data:p}", h.id, h.layout.size());
+            print_callers(&h.callers);
+            header = h.next;
+        }
+    });
+}
@@ -0,0 +1,322 @@
+//! Physical frame allocation.
+//!
+//! Every 4 KiB frame below the end of the highest usable memory map entry has
+//! a bit in a bitmap, which is set if the frame is in use. The bitmap itself
+//! lives in the first usable entry that is large enough for it, and is
+//! accessed through the higher half direct map.
+use core::slice;
+
+use limine::memory_map::{Entry, EntryType};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+};
+
+use crate::sprintln;
+
+pub const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Number of 4 KiB frames in a 2 MiB frame.
+const FRAMES_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+
+/// How many bootloader reclaimable memory map entries we remember.
+const MAX_RECLAIMABLE: usize = 64;
+
+pub struct BitmapFrameAllocator {
+    /// One bit per frame, set if the frame is in use.
+    bitmap: &'static mut [u64],
+    /// Number of frames the bitmap covers.
+    frames: usize,
+    /// Number of frames that are free.
+    free: usize,
+    /// Number of frames that can be allocated at all, used or not.
+    usable: usize,
+    /// The word to start searching for free frames at.
+    next_word: usize,
+    /// Bootloader reclaimable memory, as start and end frame indices.
+    reclaimable: [(usize, usize); MAX_RECLAIMABLE],
+    reclaimable_len: usize,
+}
+
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+fn frame_index(frame: PhysFrame<impl PageSize>) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_at(idx: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(idx as u64 * FRAME_SIZE))
+}
+
+/// The whole frames in `entry`, as start and end frame indices.
+fn frame_range(entry: &Entry) -> (usize, usize) {
+    let start = entry.base.div_ceil(FRAME_SIZE) as usize;
+    let end = ((entry.base + entry.length) / FRAME_SIZE) as usize;
+    (start, end.max(start))
+}
+
+impl BitmapFrameAllocator {
+    /// Create the allocator from the memory map.
+    ///
+    /// # SAFETY
+    ///
+    /// All frames marked as `USABLE` must really be unused, and the complete
+    /// physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn new(regions: &[&Entry], physical_memory_offset: u64) -> Self {
+        let frames = regions
+            .iter()
+            .filter(|entry| {
+                entry.entry_type == EntryType::USABLE
+                    || entry.entry_type == EntryType::BOOTLOADER_RECLAIMABLE
+            })
+            .map(|entry| frame_range(entry).1)
+            .max()
+            .expect("no usable memory");
+        let words = frames.div_ceil(64);
+        let bitmap_frames = (words * 8).div_ceil(FRAME_SIZE as usize);
+
+        let (bitmap_start, _) = regions
+            .iter()
+            .filter(|entry| entry.entry_type == EntryType::USABLE)
+            .map(|entry| frame_range(entry))
+            .find(|(start, end)| end - start >= bitmap_frames)
+            .expect("no room for the frame bitmap");
+
+        let bitmap_ptr = (physical_memory_offset + bitmap_start as u64 * FRAME_SIZE) as *mut u64;
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+        // everything is in use, except for what the memory map says is usable.
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = Self {
+            bitmap,
+            frames,
+            free: 0,
+            usable: 0,
+            next_word: 0,
+            reclaimable: [(0, 0); MAX_RECLAIMABLE],
+            reclaimable_len: 0,
+        };
+
+        for entry in regions {
+            let (start, end) = frame_range(entry);
+            match entry.entry_type {
+                EntryType::USABLE => {
+                    allocator.set_range(start, end, false);
+                    allocator.free += end - start;
+                    allocator.usable += end - start;
+                }
+                EntryType::BOOTLOADER_RECLAIMABLE => {
+                    if allocator.reclaimable_len == MAX_RECLAIMABLE {
+                        log::warn!("too many bootloader reclaimable regions, leaking some");
+                        continue;
+                    }
+                    allocator.reclaimable[allocator.reclaimable_len] = (start, end);
+                    allocator.reclaimable_len += 1;
+                }
+                _ => {}
+            }
+        }
+
+        allocator.set_range(bitmap_start, bitmap_start + bitmap_frames, true);
+        allocator.free -= bitmap_frames;
+
+        allocator
+    }
+
+    fn is_used(&self, idx: usize) -> bool {
+        self.bitmap[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn set_range(&mut self, start: usize, end: usize, used: bool) {
+        for idx in start..end {
+            if used {
+                self.bitmap[idx / 64] |= 1 << (idx % 64);
+            } else {
+                self.bitmap[idx / 64] &= !(1 << (idx % 64));
+            }
+        }
+    }
+
+    pub fn allocate(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        let word = (0..words)
+            .map(|i| (self.next_word + i) % words)
+            .find(|&word| self.bitmap[word] != u64::MAX)?;
+        let idx = word * 64 + self.bitmap[word].trailing_ones() as usize;
+        if idx >= self.frames {
+            return None;
+        }
+
+        self.bitmap[word] |= 1 << (idx % 64);
+        self.free -= 1;
+        self.next_word = word;
+        Some(frame_at(idx))
+    }
+
+    /// Allocate `count` physically contiguous frames, the first of which is
+    /// aligned to `align` frames.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(count > 0 && align.is_power_of_two());
+
+        let mut start = 0;
+        while start + count <= self.frames {
+            // the last used frame in the candidate range, if any.
+            match (start..start + count).rev().find(|&idx| self.is_used(idx)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    self.set_range(start, start + count, true);
+                    self.free -= count;
+                    return Some(frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Free a frame returned by `allocate`.
+    ///
+    /// Panics if the frame is not in use.
+    pub fn free(&mut self, frame: PhysFrame) {
+        self.free_contiguous(frame, 1);
+    }
+
+    /// Free `count` frames starting at `frame`.
+    ///
+    /// Panics if any of them is not in use.
+    pub fn free_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        let start = frame_index(frame);
+        for idx in start..start + count {
+            assert!(
+                idx < self.frames && self.is_used(idx),
+                "double free of frame {:#x}",
+                idx as u64 * FRAME_SIZE
+            );
+        }
+        self.set_range(start, start + count, false);
+        self.free += count;
+        self.next_word = self.next_word.min(start / 64);
+    }
+
+    pub fn allocate_huge(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_contiguous(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME)?;
+        Some(PhysFrame::from_start_address(frame.start_address()).unwrap())
+    }
+
+    pub fn free_huge(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.free_contiguous(frame_at(frame_index(frame)), FRAMES_PER_HUGE_FRAME);
+    }
+
+    /// Hand the bootloader reclaimable memory to the allocator.
+    ///
+    /// # SAFETY
+    ///
+    /// Nothing may use the bootloader's memory anymore: its page tables, the
+    /// stack it gave us, and any of its responses (including the memory map).
+    pub unsafe fn reclaim_bootloader_memory(&mut self) {
+        let reclaimable = &self.reclaimable[..self.reclaimable_len];
+        let frames: usize = reclaimable.iter().map(|(start, end)| end - start).sum();
+        for &(start, end) in reclaimable {
+            // not `set_range` since `self.reclaimable` is borrowed.
+            for idx in start..end {
+                self.bitmap[idx / 64] &= !(1 << (idx % 64));
+            }
+        }
+        self.free += frames;
+        self.usable += frames;
+        self.reclaimable_len = 0;
+        self.next_word = 0;
+        sprintln!("reclaimed {} KiB of bootloader memory", frames * 4);
+    }
+
+    /// Number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Number of frames that can be allocated at all, used or not.
+    pub fn usable_frames(&self) -> usize {
+        self.usable
+    }
+}
+
+/// Set up `FRAME_ALLOCATOR`.
+///
+/// # SAFETY
+///
+/// See `BitmapFrameAllocator::new`.
+pub unsafe fn init(regions: &[&Entry], physical_memory_offset: u64) {
+    let allocator = BitmapFrameAllocator::new(regions, physical_memory_offset);
+    sprintln!(
+        "{} KiB of usable memory, {} KiB bootloader reclaimable",
+        allocator.usable * 4,
+        allocator.reclaimable[..allocator.reclaimable_len]
+            .iter()
+            .map(|(start, end)| (end - start) * 4)
+            .sum::<usize>()
+    );
+    without_interrupts(|| *FRAME_ALLOCATOR.lock() = Some(allocator));
+}
+
+fn with_allocator<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> R {
+    without_interrupts(|| {
+        f(FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .expect("frame allocator not initialized"))
+    })
+}
+
+pub fn allocate_frame() -> Option<PhysFrame> {
+    with_allocator(|allocator| allocator.allocate())
+}
+
+pub fn free_frame(frame: PhysFrame) {
+    with_allocator(|allocator| allocator.free(frame))
+}
+
+pub fn allocate_huge_frame() -> Option<PhysFrame<Size2MiB>> {
+    with_allocator(|allocator| allocator.allocate_huge())
+}
+
+pub fn free_huge_frame(frame: PhysFrame<Size2MiB>) {
+    with_allocator(|allocator| allocator.free_huge(frame))
+}
+
+/// See `BitmapFrameAllocator::reclaim_bootloader_memory`.
+pub unsafe fn reclaim_bootloader_memory() {
+    with_allocator(|allocator| allocator.reclaim_bootloader_memory())
+}
+
+/// Free and usable frames.
+pub fn stats() -> (usize, usize) {
+    with_allocator(|allocator| (allocator.free_frames(), allocator.usable_frames()))
+}
+
+/// A handle to `FRAME_ALLOCATOR` for the `x86_64` crate's mappers.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        allocate_huge_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        free_frame(frame)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        free_huge_frame(frame)
+    }
+}
@@ -0,0 +1,134 @@
+//! The kernel heap.
+//!
+//! Starts out with `HEAP_SIZE` bytes mapped at `HEAP_START`, and whenever an
+//! allocation doesn't fit, maps more frames right after the end of the heap,
+//! until it reaches `HEAP_MAX_SIZE`.
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{PageSize, PageTableFlags, Size4KiB};
+
+use super::paging;
+use super::vmm::{self, VmmError};
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 256 * 1024; // 256 KiB
+
+/// The heap never grows beyond this.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+
+/// Grow by at least this much at once, so that lots of small allocations
+/// don't each need to map a page.
+const HEAP_GROW_MIN: usize = 64 * 1024;
+
+/// The backing heap of the slab caches, see `super::ALLOCATOR`.
+pub static HEAP: GrowableHeap = GrowableHeap::empty();
+
+pub struct GrowableHeap {
+    heap: Mutex<Heap>,
+}
+
+/// Sizes of the heap in bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Currently mapped.
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub max_size: usize,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> Self {
+        Self {
+            heap: Mutex::new(Heap::empty()),
+        }
+    }
+
+    /// Map enough memory after the end of the heap for `layout` to fit.
+    fn grow(heap: &mut Heap, layout: Layout) -> Result<(), VmmError> {
+        let by = (layout.size() + layout.align())
+            .max(HEAP_GROW_MIN)
+            .next_multiple_of(Size4KiB::SIZE as usize);
+        if heap.size() + by > HEAP_MAX_SIZE {
+            return Err(VmmError::OutOfMemory);
+        }
+
+        vmm::extend(HEAP_START as u64, by as u64)?;
+        unsafe { heap.extend(by) };
+        Ok(())
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        without_interrupts(|| {
+            let heap = self.heap.lock();
+            HeapStats {
+                size: heap.size(),
+                used: heap.used(),
+                free: heap.free(),
+                max_size: HEAP_MAX_SIZE,
+            }
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // interrupt handlers allocate too.
+        without_interrupts(|| {
+            let mut heap = self.heap.lock();
+            if let Ok(ptr) = heap.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if Self::grow(&mut heap, layout).is_err() {
+                return ptr::null_mut();
+            }
+            heap.allocate_first_fit(layout)
+                .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| {
+            self.heap
+                .lock()
+                .deallocate(NonNull::new_unchecked(ptr), layout)
+        })
+    }
+}
+
+/// Map the initial heap.
+pub fn init() -> Result<(), VmmError> {
+    vmm::map(
+        "heap",
+        HEAP_START as u64,
+        HEAP_SIZE as u64,
+        PageTableFlags::WRITABLE | paging::no_execute(),
+    )?;
+
+    without_interrupts(|| unsafe { HEAP.heap.lock().init(HEAP_START as *mut u8, HEAP_SIZE) });
+
+    Ok(())
+}
+
+pub fn stats() -> HeapStats {
+    HEAP.stats()
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let stats = stats();
+    panic!(
+        "failed to allocate {} bytes aligned to {}: {} of {} heap bytes used ({} free), \
+         the heap can grow up to {} bytes",
+        layout.size(),
+        layout.align(),
+        stats.used,
+        stats.size,
+        stats.free,
+        stats.max_size
+    );
+}
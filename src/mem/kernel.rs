@@ -0,0 +1,206 @@
+//! The kernel's own page tables.
+//!
+//! The bootloader maps the whole kernel image read-write-execute. We build
+//! new page tables instead, in which no page is both writable and
+//! executable:
+//!
+//! - `.text` is read-execute
+//! - `.rodata` is read-only
+//! - `.data` and `.bss` are read-write
+//! - the higher half direct map is read-write, but never executable.
+//!
+//! The section boundaries come from `linker.ld`.
+use core::arch::x86_64::__cpuid_count;
+
+use limine::memory_map::{Entry, EntryType};
+use x86_64::PhysAddr;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB};
+
+use super::frame::GlobalFrameAllocator;
+use super::paging::{self, PAGE_SIZE, PageTables};
+use super::vmm::{self, VmmError};
+
+extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// Legacy BIOS memory, where the RSDP may be, is not always in the memory
+/// map. The VGA memory in between is left out.
+const LOW_MEMORY: [(u64, u64); 2] = [(0, 0xA_0000), (0xC_0000, 0x10_0000)];
+
+/// Where the kernel was loaded, from the kernel address request.
+#[derive(Clone, Copy, Debug)]
+pub struct KernelAddress {
+    pub physical_base: u64,
+    pub virtual_base: u64,
+}
+
+pub struct Section {
+    pub name: &'static str,
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageTableFlags,
+}
+
+/// The parts of the kernel image, `.bss` is part of the data section.
+pub fn sections() -> [Section; 3] {
+    let nx = paging::no_execute();
+    let addr = |symbol: *const u8| symbol as u64;
+    [
+        Section {
+            name: "kernel text",
+            start: addr(&raw const __text_start),
+            end: addr(&raw const __text_end),
+            flags: PageTableFlags::PRESENT,
+        },
+        Section {
+            name: "kernel rodata",
+            start: addr(&raw const __rodata_start),
+            end: addr(&raw const __rodata_end),
+            flags: PageTableFlags::PRESENT | nx,
+        },
+        Section {
+            name: "kernel data",
+            start: addr(&raw const __data_start),
+            end: addr(&raw const __data_end),
+            flags: PageTableFlags::PRESENT | PageTableFlags::WRITABLE | nx,
+        },
+    ]
+}
+
+/// Whether the higher half direct map should cover `entry`.
+///
+/// The kernel image must only be reachable through its own mapping, or it
+/// would be writable after all, and the framebuffer is mapped
+/// write-combining by `mmio`. Reserved memory is mostly device memory, which
+/// must not be cached, so ACPI tables in it are mapped by `mmio` too.
+fn in_direct_map(entry: &Entry) -> bool {
+    ![
+        EntryType::RESERVED,
+        EntryType::BAD_MEMORY,
+        EntryType::KERNEL_AND_MODULES,
+        EntryType::FRAMEBUFFER,
+    ]
+    .contains(&entry.entry_type)
+}
+
+/// Map `start..end` of physical memory into the direct map, with 2 MiB
+/// pages where possible.
+fn map_direct(tables: &mut PageTables, start: u64, end: u64) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | paging::no_execute();
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let fa = &mut GlobalFrameAllocator;
+
+    let mut phys = start / PAGE_SIZE * PAGE_SIZE;
+    let end = end.next_multiple_of(PAGE_SIZE);
+    while phys < end {
+        let virt = paging::phys_to_virt(PhysAddr::new(phys));
+        if phys.is_multiple_of(Size2MiB::SIZE)
+            && virt.is_multiple_of(Size2MiB::SIZE)
+            && end - phys >= Size2MiB::SIZE
+        {
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+            match unsafe { tables.map_huge_to(virt, frame, flags, table_flags, fa) } {
+                Ok(()) => {
+                    phys += Size2MiB::SIZE;
+                    continue;
+                }
+                Err(MapToError::FrameAllocationFailed) => {
+                    panic!("out of memory while building the direct map")
+                }
+                // a neighbouring entry mapped part of it already, fill in the
+                // rest with small pages.
+                Err(_) => {}
+            }
+        }
+
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys));
+        // entries may share pages at their edges, so it may be mapped already.
+        if let Err(MapToError::FrameAllocationFailed) =
+            unsafe { tables.map_to(virt, frame, flags, table_flags, fa) }
+        {
+            panic!("out of memory while building the direct map");
+        }
+        phys += PAGE_SIZE;
+    }
+}
+
+/// Turn on the protection features the CPU has.
+fn enable_protection() {
+    let nx = __cpuid_count(0x8000_0001, 0).edx & (1 << 20) != 0;
+    let features = __cpuid_count(7, 0).ebx;
+    let smep = features & (1 << 7) != 0;
+    let smap = features & (1 << 20) != 0;
+
+    unsafe {
+        if nx {
+            Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        // also keep the kernel from writing to read-only pages.
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+        Cr4::update(|cr4| {
+            cr4.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+            cr4.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+        });
+    }
+    log::info!("memory protection: NX {nx}, WP true, SMEP {smep}, SMAP {smap}");
+}
+
+/// Build the kernel page tables and switch to them.
+///
+/// Nothing mapped before survives, except for the kernel image and the
+/// direct map, so this must run before the first `vmm` mapping.
+///
+/// SAFETY: `kernel` must be where the kernel really is, and everything in
+/// use (like the stack) must be in the direct map.
+pub unsafe fn init(kernel: KernelAddress, memory_regions: &[&Entry]) {
+    enable_protection();
+
+    let mut tables =
+        PageTables::new(&mut GlobalFrameAllocator).expect("no memory for the kernel page tables");
+
+    for section in sections() {
+        let start = section.start / PAGE_SIZE * PAGE_SIZE;
+        let end = section.end.next_multiple_of(PAGE_SIZE);
+        for virt in (start..end).step_by(PAGE_SIZE as usize) {
+            let phys = PhysAddr::new(virt - kernel.virtual_base + kernel.physical_base);
+            tables
+                .map_to(
+                    virt,
+                    PhysFrame::containing_address(phys),
+                    section.flags,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    &mut GlobalFrameAllocator,
+                )
+                .expect("failed to map the kernel");
+        }
+    }
+
+    for (start, end) in LOW_MEMORY {
+        map_direct(&mut tables, start, end);
+    }
+    for entry in memory_regions.iter().filter(|entry| in_direct_map(entry)) {
+        map_direct(&mut tables, entry.base, entry.base + entry.length);
+    }
+
+    tables.activate();
+    vmm::init(tables);
+
+    for section in sections() {
+        let start = section.start / PAGE_SIZE * PAGE_SIZE;
+        let size = section.end.next_multiple_of(PAGE_SIZE) - start;
+        match vmm::register(section.name, start, size, section.flags) {
+            Ok(_) => {}
+            Err(VmmError::Overlaps(other)) => panic!("{} overlaps {other}", section.name),
+            Err(error) => panic!("failed to register {}: {error:?}", section.name),
+        }
+    }
+}
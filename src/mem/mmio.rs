@@ -0,0 +1,130 @@
+//! Mapping device memory.
+//!
+//! Firmware tables in memory that the direct map leaves out are mapped here
+//! as well, as normal write-back memory.
+//!
+//! The memory type of a page is picked from the page attribute table (PAT)
+//! by its PAT, PCD and PWT bits. We program the PAT like Linux does, so that
+//! the entries selected by PCD and PWT alone (all we need for 4 KiB pages)
+//! are write-back, write-combining, uncached-minus and uncacheable.
+//!
+//! Device registers are mapped uncacheable, so every access reaches the
+//! device in order, while the framebuffer is mapped write-combining, which
+//! lets the CPU merge writes into bursts.
+//!
+//! See Intel SDM Vol. 3A section 12.12 ("Page Attribute Table (PAT)").
+use core::arch::x86_64::__cpuid;
+use core::ptr::NonNull;
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicBool, AtomicU64};
+
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+
+use super::paging::{self, PAGE_SIZE};
+use super::vmm::{self, VmmError};
+
+const IA32_PAT: u32 = 0x277;
+
+// memory types, as encoded in the PAT.
+const UC: u64 = 0x00;
+const WC: u64 = 0x01;
+const WP: u64 = 0x05;
+const WT: u64 = 0x04;
+const WB: u64 = 0x06;
+const UC_MINUS: u64 = 0x07;
+
+/// Indexed by PAT << 2 | PCD << 1 | PWT.
+const PAT_ENTRIES: [u64; 8] = [WB, WC, UC_MINUS, UC, WB, WP, UC_MINUS, WT];
+
+/// Device memory is mapped here.
+const MMIO_START: u64 = 0x_5555_0000_0000;
+const MMIO_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+static PAT_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Where the next mapping goes.
+static NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CacheMode {
+    /// For device registers.
+    Uncacheable,
+    /// For framebuffers, falls back to uncacheable without PAT.
+    WriteCombining,
+    /// For firmware tables, which are normal memory.
+    WriteBack,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        let uncacheable = PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        match self {
+            CacheMode::Uncacheable => uncacheable,
+            // without PAT this would be write-through.
+            CacheMode::WriteCombining if !PAT_SUPPORTED.load(Relaxed) => uncacheable,
+            CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteBack => PageTableFlags::empty(),
+        }
+    }
+}
+
+/// Program the PAT, if there is one.
+pub fn init() {
+    if __cpuid(1).edx & (1 << 16) == 0 {
+        log::warn!("no PAT, the framebuffer will be uncacheable");
+        return;
+    }
+
+    let pat = PAT_ENTRIES
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, ty)| pat | ty << (i * 8));
+
+    // the procedure from Intel SDM Vol. 3A section 12.12.4: disable caching
+    // and flush everything, so that no stale lines of the old type remain.
+    without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write(cr0 | Cr0Flags::CACHE_DISABLE);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+        Msr::new(IA32_PAT).write(pat);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        tlb::flush_all();
+        Cr0::write(cr0);
+    });
+    PAT_SUPPORTED.store(true, Relaxed);
+}
+
+/// Map `size` bytes of device memory at `phys`.
+///
+/// The memory is also in the higher half direct map if the bootloader put it
+/// there, but must not be accessed through it: mapping the same memory with
+/// different types is undefined.
+pub fn map(
+    name: &'static str,
+    phys: u64,
+    size: u64,
+    cache: CacheMode,
+) -> Result<NonNull<u8>, VmmError> {
+    let offset = phys % PAGE_SIZE;
+    let size = (offset + size).next_multiple_of(PAGE_SIZE);
+    let start = NEXT.fetch_add(size, Relaxed);
+    if start + size > MMIO_START + MMIO_SIZE {
+        return Err(VmmError::OutOfMemory);
+    }
+
+    let flags = PageTableFlags::WRITABLE | paging::no_execute() | cache.flags();
+    vmm::map_physical(name, start, PhysAddr::new(phys - offset), size, flags)?;
+    Ok(NonNull::new((start + offset) as *mut u8).unwrap())
+}
+
+/// Unmap what `map` mapped at `ptr`. The addresses aren't handed out again.
+pub fn unmap(ptr: NonNull<u8>) -> Result<(), VmmError> {
+    let start = ptr.as_ptr() as u64 / PAGE_SIZE * PAGE_SIZE;
+    vmm::unmap(start).map(|_| ())
+}
@@ -0,0 +1,306 @@
+//! Page tables with four or five levels.
+//!
+//! The bootloader enables 5-level paging (LA57) if both it and the CPU
+//! support it. Virtual addresses then have 57 instead of 48 bits, and the
+//! higher half direct map moves out of the 48-bit canonical range, so
+//! addresses are plain `u64`s here: `VirtAddr` only knows about 48 bits.
+use core::arch::asm;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+
+use limine::paging::Mode;
+use spin::Once;
+use x86_64::PhysAddr;
+use x86_64::registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+};
+
+pub const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PagingMode {
+    FourLevel,
+    FiveLevel,
+}
+
+impl PagingMode {
+    pub fn levels(self) -> u32 {
+        match self {
+            PagingMode::FourLevel => 4,
+            PagingMode::FiveLevel => 5,
+        }
+    }
+
+    /// Number of significant bits in a virtual address.
+    pub fn address_bits(self) -> u32 {
+        12 + 9 * self.levels()
+    }
+
+    /// Sign extend `addr` from its highest significant bit.
+    pub fn canonicalize(self, addr: u64) -> u64 {
+        let shift = 64 - self.address_bits();
+        ((addr << shift) as i64 >> shift) as u64
+    }
+
+    pub fn is_canonical(self, addr: u64) -> bool {
+        self.canonicalize(addr) == addr
+    }
+}
+
+static MODE: Once<PagingMode> = Once::new();
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Find out which paging mode we are running in.
+///
+/// `granted` is what the bootloader says it set up, if it knows about the
+/// paging mode request at all, but CR4 has the final word.
+pub fn init(physical_memory_offset: u64, granted: Option<Mode>) -> PagingMode {
+    let mode = if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        PagingMode::FiveLevel
+    } else {
+        PagingMode::FourLevel
+    };
+    let expected = if granted == Some(Mode::FIVE_LEVEL) {
+        PagingMode::FiveLevel
+    } else {
+        PagingMode::FourLevel
+    };
+    if mode != expected {
+        log::warn!("bootloader reported {expected:?} paging, but CR4 says {mode:?}");
+    }
+    log::info!(
+        "paging: {} levels, {}-bit virtual addresses",
+        mode.levels(),
+        mode.address_bits()
+    );
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Relaxed);
+    *MODE.call_once(|| mode)
+}
+
+pub fn mode() -> PagingMode {
+    *MODE.get().expect("paging not initialized")
+}
+
+/// Where `phys` is in the higher half direct map.
+pub fn phys_to_virt(phys: PhysAddr) -> u64 {
+    PHYSICAL_MEMORY_OFFSET.load(Relaxed) + phys.as_u64()
+}
+
+/// `NO_EXECUTE`, if it is enabled. The bit is reserved otherwise.
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Remove the translation for `addr` from the TLB.
+pub fn flush(addr: u64) {
+    // `tlb::flush` takes a `VirtAddr`, which can't hold 57-bit addresses.
+    unsafe { asm!("invlpg [{}]", in(reg) addr, options(nostack, preserves_flags)) };
+}
+
+/// The index into the table at `level` (1 is the lowest) for `addr`.
+fn table_index(addr: u64, level: u32) -> usize {
+    ((addr >> (12 + 9 * (level - 1))) & 0x1FF) as usize
+}
+
+/// SAFETY: `frame` must contain a page table, and there must be no other
+/// references to it.
+unsafe fn table_at<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    &mut *(phys_to_virt(frame.start_address()) as *mut PageTable)
+}
+
+enum WalkError {
+    NotMapped,
+    HugePage,
+}
+
+/// The table `entry` points to.
+unsafe fn next_table<'a>(entry: &PageTableEntry) -> Result<&'a mut PageTable, WalkError> {
+    if entry.is_unused() || !entry.flags().contains(PageTableFlags::PRESENT) {
+        return Err(WalkError::NotMapped);
+    }
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(WalkError::HugePage);
+    }
+    Ok(table_at(PhysFrame::containing_address(entry.addr())))
+}
+
+/// The table `entry` points to, creating it if there is none.
+unsafe fn next_table_create<'a, S: PageSize>(
+    entry: &mut PageTableEntry,
+    table_flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<&'a mut PageTable, MapToError<S>> {
+    if entry.is_unused() {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        table_at(frame).zero();
+        entry.set_frame(frame, table_flags | PageTableFlags::PRESENT);
+    } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(MapToError::ParentEntryHugePage);
+    } else if !entry.flags().contains(table_flags) {
+        entry.set_flags(entry.flags() | table_flags);
+    }
+    Ok(table_at(PhysFrame::containing_address(entry.addr())))
+}
+
+/// A page table hierarchy, accessed through the higher half direct map.
+pub struct PageTables {
+    root: PhysFrame,
+    mode: PagingMode,
+}
+
+impl PageTables {
+    /// New page tables without any mappings.
+    pub fn new(
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Self, MapToError<Size4KiB>> {
+        let root = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { table_at(root).zero() };
+        Ok(Self { root, mode: mode() })
+    }
+
+    /// Switch to these page tables.
+    ///
+    /// SAFETY: everything in use, like the code, the stack and the current
+    /// references, must be mapped the same way in them.
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.root, Cr3Flags::empty());
+    }
+
+    /// The level 1 entry for `addr`.
+    fn leaf(&mut self, addr: u64) -> Result<&mut PageTableEntry, WalkError> {
+        let mut table = unsafe { table_at(self.root) };
+        for level in (2..=self.mode.levels()).rev() {
+            table = unsafe { next_table(&table[table_index(addr, level)])? };
+        }
+        Ok(&mut table[table_index(addr, 1)])
+    }
+
+    /// Map the 4 KiB page at `addr` to `frame`.
+    ///
+    /// Missing tables on the way are allocated, and all of them get at least
+    /// `table_flags`.
+    ///
+    /// SAFETY: the mapping must not break memory safety, e.g. by aliasing
+    /// memory that is in use.
+    pub unsafe fn map_to(
+        &mut self,
+        addr: u64,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        table_flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        self.map_at_level(addr, frame, flags, table_flags, frame_allocator, 1)
+    }
+
+    /// Map the 2 MiB page at `addr` to `frame`, like `map_to`.
+    ///
+    /// SAFETY: see `map_to`.
+    pub unsafe fn map_huge_to(
+        &mut self,
+        addr: u64,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        table_flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), MapToError<Size2MiB>> {
+        let flags = flags | PageTableFlags::HUGE_PAGE;
+        self.map_at_level(addr, frame, flags, table_flags, frame_allocator, 2)
+    }
+
+    unsafe fn map_at_level<S: PageSize>(
+        &mut self,
+        addr: u64,
+        frame: PhysFrame<S>,
+        flags: PageTableFlags,
+        table_flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        level: u32,
+    ) -> Result<(), MapToError<S>> {
+        debug_assert!(self.mode.is_canonical(addr) && addr.is_multiple_of(S::SIZE));
+        let mut table = table_at(self.root);
+        for level in (level + 1..=self.mode.levels()).rev() {
+            let entry = &mut table[table_index(addr, level)];
+            table = next_table_create(entry, table_flags, frame_allocator)?;
+        }
+
+        let entry = &mut table[table_index(addr, level)];
+        if !entry.is_unused() {
+            return Err(MapToError::PageAlreadyMapped(
+                PhysFrame::containing_address(entry.addr()),
+            ));
+        }
+        entry.set_addr(frame.start_address(), flags);
+        flush(addr);
+        Ok(())
+    }
+
+    /// Unmap the 4 KiB page at `addr`, returns the frame it was mapped to.
+    pub fn unmap(&mut self, addr: u64) -> Result<PhysFrame, UnmapError> {
+        let entry = self.leaf(addr).map_err(|e| match e {
+            WalkError::NotMapped => UnmapError::PageNotMapped,
+            WalkError::HugePage => UnmapError::ParentEntryHugePage,
+        })?;
+        if entry.is_unused() {
+            return Err(UnmapError::PageNotMapped);
+        }
+        let frame = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        flush(addr);
+        Ok(frame)
+    }
+
+    /// Replace the flags of the 4 KiB page at `addr`.
+    ///
+    /// SAFETY: see `map_to`.
+    pub unsafe fn update_flags(
+        &mut self,
+        addr: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let entry = self.leaf(addr).map_err(|e| match e {
+            WalkError::NotMapped => FlagUpdateError::PageNotMapped,
+            WalkError::HugePage => FlagUpdateError::ParentEntryHugePage,
+        })?;
+        if entry.is_unused() {
+            return Err(FlagUpdateError::PageNotMapped);
+        }
+        entry.set_flags(flags);
+        flush(addr);
+        Ok(())
+    }
+
+    /// The physical address `addr` is mapped to and the flags of the page,
+    /// which may be a huge page.
+    pub fn translate(&self, addr: u64) -> Option<(PhysAddr, PageTableFlags)> {
+        let mut table = unsafe { table_at(self.root) };
+        for level in (1..=self.mode.levels()).rev() {
+            let entry = &table[table_index(addr, level)];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            // 2 MiB and 1 GiB pages.
+            if level == 1 || (level <= 3 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                let page_mask = (1 << (12 + 9 * (level - 1))) - 1;
+                return Some((entry.addr() + (addr & page_mask), flags));
+            }
+            table = unsafe { table_at(PhysFrame::containing_address(entry.addr())) };
+        }
+        unreachable!()
+    }
+}
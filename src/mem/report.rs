@@ -0,0 +1,154 @@
+//! Memory usage report.
+//!
+//! `print_report` shows what the bootloader's memory map contained, how much
+//! physical memory and heap is in use, what the slab caches hold and how
+//! large the kernel image is. It runs once at boot, and again whenever `m`
+//! arrives on the serial port.
+//!
+//! The heap only gives the slab caches whole pages, which they never give
+//! back, so its free memory is in one piece at the end of it. Fragmentation
+//! shows up in the caches instead, as free objects that only allocations of
+//! their size can use.
+//!
+//! The serial interrupt handler only asks for the report with `request`,
+//! since building it allocates and takes the allocator and framebuffer
+//! locks, which the interrupted code may hold. `kernel_main` prints it.
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::Relaxed;
+
+use limine::memory_map::{Entry, EntryType};
+use spin::Once;
+
+use super::frame::{self, FRAME_SIZE};
+use super::slab::{self, SLAB_SIZE};
+use super::{heap, kernel};
+use crate::{print, sprint};
+
+const ENTRY_TYPES: [(EntryType, &str); 8] = [
+    (EntryType::USABLE, "usable"),
+    (EntryType::RESERVED, "reserved"),
+    (EntryType::ACPI_RECLAIMABLE, "ACPI reclaimable"),
+    (EntryType::ACPI_NVS, "ACPI NVS"),
+    (EntryType::BAD_MEMORY, "bad memory"),
+    (EntryType::BOOTLOADER_RECLAIMABLE, "bootloader reclaimable"),
+    (EntryType::KERNEL_AND_MODULES, "kernel and modules"),
+    (EntryType::FRAMEBUFFER, "framebuffer"),
+];
+
+/// Number of entries and bytes of each type in the memory map.
+///
+/// The memory map itself is in bootloader reclaimable memory, so we only
+/// keep the totals.
+static MEMORY_MAP: Once<[(usize, u64); ENTRY_TYPES.len()]> = Once::new();
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub(super) fn record_memory_map(regions: &[&Entry]) {
+    MEMORY_MAP.call_once(|| {
+        let mut totals = [(0, 0); ENTRY_TYPES.len()];
+        for entry in regions {
+            if let Some(i) = ENTRY_TYPES
+                .iter()
+                .position(|(ty, _)| *ty == entry.entry_type)
+            {
+                totals[i].0 += 1;
+                totals[i].1 += entry.length;
+            }
+        }
+        totals
+    });
+}
+
+fn kib(bytes: u64) -> u64 {
+    bytes / 1024
+}
+
+/// Write the report to `out`.
+pub fn report(out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "memory map:")?;
+    for ((_, name), (entries, bytes)) in ENTRY_TYPES.iter().zip(MEMORY_MAP.get().unwrap()) {
+        if *entries != 0 {
+            writeln!(
+                out,
+                "  {name:<22} {:>9} KiB in {entries} entries",
+                kib(*bytes)
+            )?;
+        }
+    }
+
+    let (free, usable) = frame::stats();
+    writeln!(
+        out,
+        "physical memory: {} of {} KiB free",
+        kib(free as u64 * FRAME_SIZE),
+        kib(usable as u64 * FRAME_SIZE)
+    )?;
+
+    let heap = heap::stats();
+    writeln!(
+        out,
+        "heap: {} of {} KiB used, {} KiB free, can grow to {} KiB",
+        kib(heap.used as u64),
+        kib(heap.size as u64),
+        kib(heap.free as u64),
+        kib(heap.max_size as u64),
+    )?;
+
+    writeln!(out, "slab caches:")?;
+    writeln!(
+        out,
+        "  {:>5} {:>5} {:>7} {:>9} {:>9}",
+        "size", "pages", "in use", "allocs", "frees"
+    )?;
+    let caches = slab::stats();
+    for cache in caches {
+        writeln!(
+            out,
+            "  {:>5} {:>5} {:>7} {:>9} {:>9}",
+            cache.size, cache.pages, cache.in_use, cache.allocs, cache.frees
+        )?;
+    }
+    let cached: usize = caches.iter().map(|cache| cache.pages * SLAB_SIZE).sum();
+    let fragmented: usize = caches.iter().map(|cache| cache.free_bytes()).sum();
+    writeln!(
+        out,
+        "fragmentation: {} of {} KiB in the caches are free objects",
+        kib(fragmented as u64),
+        kib(cached as u64)
+    )?;
+
+    write!(out, "kernel:")?;
+    for section in kernel::sections() {
+        write!(
+            out,
+            " {} {} KiB",
+            section.name.trim_start_matches("kernel "),
+            kib(section.end - section.start)
+        )?;
+    }
+    writeln!(out)
+}
+
+/// Ask for a report. Safe to call from interrupt handlers.
+pub fn request() {
+    REQUESTED.store(true, Relaxed);
+}
+
+/// Whether a report was requested since the last call.
+pub fn take_request() -> bool {
+    REQUESTED.swap(false, Relaxed)
+}
+
+/// Print the report to serial and, if it is set up, the screen.
+///
+/// Must not be called from interrupt handlers, see `request`.
+pub fn print_report() {
+    let mut s = String::new();
+    report(&mut s).expect("formatting to a String failed");
+    sprint!("{s}");
+    if crate::draw::initialized() {
+        print!("{s}");
+    }
+}
@@ -0,0 +1,238 @@
+//! Slab caches for small allocations.
+//!
+//! Allocations up to 2 KiB are rounded up to the next power
+//! of two and served from a cache of equally sized objects, which takes
+//! constant time. The caches get their memory in whole pages from the
+//! backing heap.
+//!
+//! Pages are never given back to the backing heap, a cache stays as large as
+//! it ever was.
+//!
+//! Larger allocations get whole pages of their own in `LARGE_START`, which
+//! are unmapped again when they are freed, instead of searching the backing
+//! heap's free list.
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PageTableFlags;
+
+use super::heap::GrowableHeap;
+use super::paging::{self, PAGE_SIZE};
+use super::vmm;
+
+/// Object sizes of the caches.
+const SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The caches get their memory from the backing heap in chunks of this size.
+pub const SLAB_SIZE: usize = 4096;
+
+/// Allocations too large for the caches are mapped here.
+const LARGE_START: u64 = 0x_7777_0000_0000;
+const LARGE_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+/// A free object, the first bytes of which point to the next free one.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+struct Cache {
+    free_list: Option<NonNull<FreeObject>>,
+    stats: CacheStats,
+}
+
+// SAFETY: the objects are only accessed with the cache's lock held.
+unsafe impl Send for Cache {}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    /// Object size in bytes.
+    pub size: usize,
+    /// Pages taken from the backing heap.
+    pub pages: usize,
+    pub in_use: usize,
+    pub allocs: u64,
+    pub frees: u64,
+}
+
+impl CacheStats {
+    /// Bytes of the cache's pages that hold no object, which only
+    /// allocations of this size can use.
+    pub fn free_bytes(&self) -> usize {
+        self.pages * SLAB_SIZE - self.in_use * self.size
+    }
+}
+
+impl Cache {
+    const fn new(size: usize) -> Self {
+        Self {
+            free_list: None,
+            stats: CacheStats {
+                size,
+                pages: 0,
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    /// Cut a new page from `backing` into objects.
+    unsafe fn refill(&mut self, backing: &GrowableHeap) -> bool {
+        let page = backing.alloc(Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE));
+        if page.is_null() {
+            return false;
+        }
+        self.stats.pages += 1;
+
+        for offset in (0..SLAB_SIZE).step_by(self.stats.size).rev() {
+            let object = page.add(offset).cast::<FreeObject>();
+            object.write(FreeObject {
+                next: self.free_list,
+            });
+            self.free_list = NonNull::new(object);
+        }
+        true
+    }
+
+    unsafe fn alloc(&mut self, backing: &GrowableHeap) -> *mut u8 {
+        if self.free_list.is_none() && !self.refill(backing) {
+            return ptr::null_mut();
+        }
+        let object = self.free_list.unwrap();
+        self.free_list = object.as_ref().next;
+        self.stats.in_use += 1;
+        self.stats.allocs += 1;
+        object.as_ptr().cast()
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let object = ptr.cast::<FreeObject>();
+        object.write(FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = NonNull::new(object);
+        self.stats.in_use -= 1;
+        self.stats.frees += 1;
+    }
+}
+
+pub struct SlabAllocator {
+    caches: [Mutex<Cache>; SIZES.len()],
+    backing: &'static GrowableHeap,
+}
+
+/// The cache for `layout`, if it is small enough for one.
+///
+/// Objects are aligned to their size, since the sizes are powers of two and
+/// the pages are aligned to `SLAB_SIZE`.
+fn cache_index(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZES.iter().position(|&class| size <= class)
+}
+
+/// Map whole pages for an allocation too large for the caches.
+fn alloc_pages(layout: &Layout) -> *mut u8 {
+    let size = (layout.size() as u64).next_multiple_of(PAGE_SIZE);
+    let align = (layout.align() as u64).max(PAGE_SIZE);
+    let area = LARGE_START..LARGE_START + LARGE_SIZE;
+    let flags = PageTableFlags::WRITABLE | paging::no_execute();
+    match vmm::map_in("large allocation", area, size, align, flags) {
+        Ok(region) => region.start as *mut u8,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Unmap an allocation made by `alloc_pages`, which frees its frames.
+fn dealloc_pages(ptr: *mut u8) {
+    if let Err(error) = vmm::unmap(ptr as u64) {
+        panic!("bad free of a large allocation at {ptr:p}: {error:?}");
+    }
+}
+
+impl SlabAllocator {
+    pub const fn new(backing: &'static GrowableHeap) -> Self {
+        let mut caches = [const { Mutex::new(Cache::new(0)) }; SIZES.len()];
+        let mut i = 0;
+        while i < SIZES.len() {
+            caches[i] = Mutex::new(Cache::new(SIZES[i]));
+            i += 1;
+        }
+        Self { caches, backing }
+    }
+
+    pub fn stats(&self) -> [CacheStats; SIZES.len()] {
+        core::array::from_fn(|i| without_interrupts(|| self.caches[i].lock().stats))
+    }
+}
+
+/// Statistics of each cache of the global allocator.
+pub fn stats() -> [CacheStats; SIZES.len()] {
+    super::ALLOCATOR.stats()
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match cache_index(&layout) {
+            // interrupt handlers allocate too.
+            Some(i) => without_interrupts(|| self.caches[i].lock().alloc(self.backing)),
+            None => alloc_pages(&layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match cache_index(&layout) {
+            Some(i) => without_interrupts(|| self.caches[i].lock().dealloc(ptr)),
+            None => dealloc_pages(ptr),
+        }
+    }
+}
+
+/// Allocate and free a mix of small objects like the boot code does, using
+/// both the slab caches and the backing heap directly, and print how many
+/// TSC cycles each takes per operation.
+#[cfg(feature = "alloc-bench")]
+pub fn benchmark() {
+    use crate::sprintln;
+    use crate::utils::rdtsc;
+
+    const OBJECT_SIZES: [usize; 8] = [8, 24, 40, 100, 200, 500, 1000, 2000];
+    const OBJECTS: usize = 512;
+
+    fn workload(allocator: &dyn GlobalAlloc) -> u64 {
+        let mut objects = [(ptr::null_mut(), Layout::new::<u8>()); OBJECTS];
+        let start = rdtsc();
+        unsafe {
+            for (i, object) in objects.iter_mut().enumerate() {
+                let layout =
+                    Layout::from_size_align(OBJECT_SIZES[i % OBJECT_SIZES.len()], 8).unwrap();
+                *object = (allocator.alloc(layout), layout);
+            }
+            // fragment the free list, then fill the holes again.
+            for (ptr, layout) in objects.iter().step_by(2) {
+                allocator.dealloc(*ptr, *layout);
+            }
+            for (ptr, layout) in objects.iter_mut().step_by(2) {
+                *ptr = allocator.alloc(*layout);
+            }
+            for (ptr, layout) in objects {
+                allocator.dealloc(ptr, layout);
+            }
+        }
+        rdtsc() - start
+    }
+
+    // allocations and frees.
+    let ops = OBJECTS as u64 * 3;
+    // the first run pays for growing the caches and the heap.
+    workload(&super::ALLOCATOR);
+    workload(super::ALLOCATOR.backing);
+    let slab = workload(&super::ALLOCATOR);
+    let heap = workload(super::ALLOCATOR.backing);
+    sprintln!(
+        "allocator benchmark: slab {} cycles/op, linked list {} cycles/op",
+        slab / ops,
+        heap / ops
+    );
+}
@@ -0,0 +1,102 @@
+//! Kernel stacks with guard pages.
+//!
+//! Every stack gets its own slot in the stack area, at the top of the slot,
+//! and the rest of the slot below it stays unmapped. Running off the end of
+//! a stack thus faults instead of silently overwriting whatever is below.
+//!
+//! The CPU can't push the exception frame for that fault onto the same
+//! stack either, so it usually turns into a double fault, which runs on an
+//! IST stack of its own.
+use core::arch::asm;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PageTableFlags;
+
+use super::paging::{self, PAGE_SIZE};
+use super::vmm::{self, VmmError};
+
+const STACKS_START: u64 = 0x_6666_0000_0000;
+
+/// Stacks (and their guard pages) are at most this large.
+const SLOT_SIZE: u64 = 1024 * 1024; // 1 MiB
+const MAX_STACKS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stack {
+    pub name: &'static str,
+    /// The lowest mapped address.
+    pub bottom: u64,
+    /// One past the highest mapped address, the initial stack pointer.
+    pub top: u64,
+}
+
+impl Stack {
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+}
+
+static STACKS: Mutex<[Option<Stack>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+/// Map a new stack of `size` bytes, with at least one guard page below it.
+pub fn allocate(name: &'static str, size: u64) -> Result<Stack, VmmError> {
+    let size = size.next_multiple_of(PAGE_SIZE);
+    assert!(size <= SLOT_SIZE - PAGE_SIZE, "stack {name} is too large");
+
+    without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks
+            .iter()
+            .position(Option::is_none)
+            .ok_or(VmmError::TooManyRegions)?;
+
+        let top = STACKS_START + (slot as u64 + 1) * SLOT_SIZE;
+        let stack = Stack {
+            name,
+            bottom: top - size,
+            top,
+        };
+        let flags = PageTableFlags::WRITABLE | paging::no_execute();
+        vmm::map(name, stack.bottom, size, flags)?;
+        stacks[slot] = Some(stack);
+        Ok(stack)
+    })
+}
+
+/// The stack whose guard pages contain `addr`.
+///
+/// Called from exception handlers, so it gives up instead of waiting if the
+/// stacks are locked.
+pub fn guard_hit(addr: u64) -> Option<Stack> {
+    let slot = addr.checked_sub(STACKS_START)? / SLOT_SIZE;
+    let stack = STACKS.try_lock()?.get(slot as usize).copied().flatten()?;
+    (addr < stack.bottom).then_some(stack)
+}
+
+/// The stack `addr` is on, if it is on one of ours.
+///
+/// Like `guard_hit`, this gives up if the stacks are locked, since the
+/// debug allocator calls it from within any allocation.
+#[cfg(feature = "debug-alloc")]
+pub fn containing(addr: u64) -> Option<Stack> {
+    let slot = addr.checked_sub(STACKS_START)? / SLOT_SIZE;
+    let stack = STACKS.try_lock()?.get(slot as usize).copied().flatten()?;
+    (stack.bottom..stack.top).contains(&addr).then_some(stack)
+}
+
+/// Continue with `entry` on `stack`, abandoning the current one.
+///
+/// SAFETY: nothing may refer to the current stack anymore.
+pub unsafe fn switch_to(stack: Stack, entry: extern "C" fn() -> !) -> ! {
+    // the stack is 16 byte aligned before the call, like the ABI wants.
+    asm!(
+        "mov rsp, {top}",
+        "xor ebp, ebp",
+        "call {entry}",
+        "ud2",
+        top = in(reg) stack.top,
+        entry = in(reg) entry,
+        options(noreturn),
+    )
+}
@@ -0,0 +1,415 @@
+//! The kernel address space.
+//!
+//! Everything the kernel maps after boot goes through here, so that it ends
+//! up in a named region and two regions can't overlap. Memory the bootloader
+//! mapped for us (like the framebuffer) can be registered as a region too,
+//! without touching the page tables.
+//!
+//! Addresses are `u64`s instead of `VirtAddr`s, see `paging`.
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, PhysFrame, Size4KiB};
+
+use super::frame::{self, GlobalFrameAllocator};
+use super::paging::{self, PAGE_SIZE, PageTables};
+
+/// How many regions we can keep track of. Every large heap allocation is a
+/// region of its own, see `slab`.
+const MAX_REGIONS: usize = 256;
+
+/// Where the frames of a region come from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backing {
+    /// Frames from the frame allocator, freed again on unmap.
+    Allocated,
+    /// A fixed physical range, like device registers.
+    Physical(PhysAddr),
+    /// Mapped by the bootloader, we only keep track of it.
+    Existing,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: u64,
+    /// In bytes, always a multiple of the page size.
+    pub size: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    fn overlaps(&self, start: u64, size: u64) -> bool {
+        start < self.end() && self.start < start + size
+    }
+
+    fn pages(&self) -> impl Iterator<Item = u64> {
+        page_range(self.start, self.size)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum VmmError {
+    /// The range overlaps the region with this name.
+    Overlaps(&'static str),
+    /// No region starts at the address, or the range isn't inside one.
+    NoRegion,
+    /// Addresses and sizes need to be page aligned.
+    Unaligned,
+    /// The address isn't canonical in the current paging mode.
+    NonCanonical,
+    TooManyRegions,
+    /// Something is mapped there already, but no region knows about it.
+    AlreadyMapped,
+    /// Memory that should be mapped already isn't.
+    NotMapped,
+    OutOfMemory,
+}
+
+impl From<MapToError<Size4KiB>> for VmmError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => VmmError::OutOfMemory,
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                VmmError::AlreadyMapped
+            }
+        }
+    }
+}
+
+fn page_range(start: u64, size: u64) -> impl Iterator<Item = u64> {
+    (start..start + size).step_by(PAGE_SIZE as usize)
+}
+
+fn check_range(start: u64, size: u64) -> Result<(), VmmError> {
+    if !start.is_multiple_of(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) || size == 0 {
+        return Err(VmmError::Unaligned);
+    }
+    let end = start.checked_add(size).ok_or(VmmError::NonCanonical)?;
+    let mode = paging::mode();
+    // the range must not cross the hole in the middle of the address space,
+    // which is where the sign bit of the last byte would differ.
+    let crosses_hole = (start as i64).is_negative() != ((end - 1) as i64).is_negative();
+    if !mode.is_canonical(start) || !mode.is_canonical(end - 1) || crosses_hole {
+        return Err(VmmError::NonCanonical);
+    }
+    Ok(())
+}
+
+pub struct AddressSpace {
+    page_table: PageTables,
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl AddressSpace {
+    pub fn new(page_table: PageTables) -> Self {
+        Self {
+            page_table,
+            regions: [None; MAX_REGIONS],
+        }
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().flatten()
+    }
+
+    fn region_index(&self, start: u64) -> Result<usize, VmmError> {
+        self.regions
+            .iter()
+            .position(|r| r.is_some_and(|r| r.start == start))
+            .ok_or(VmmError::NoRegion)
+    }
+
+    /// Find a slot for a new region at `start`.
+    fn check_free(&self, start: u64, size: u64) -> Result<usize, VmmError> {
+        check_range(start, size)?;
+        if let Some(region) = self.regions().find(|r| r.overlaps(start, size)) {
+            return Err(VmmError::Overlaps(region.name));
+        }
+        self.regions
+            .iter()
+            .position(Option::is_none)
+            .ok_or(VmmError::TooManyRegions)
+    }
+
+    /// Map the pages of `region`, starting `first` bytes into it, and undo
+    /// everything if that fails halfway.
+    fn map_pages(&mut self, region: &Region, first: u64) -> Result<(), VmmError> {
+        let pages = page_range(region.start + first, region.size - first);
+        for (i, page) in pages.enumerate() {
+            let offset = first + i as u64 * PAGE_SIZE;
+            if let Err(error) = self.map_page(region, page, offset) {
+                self.unmap_pages(region, page_range(region.start + first, offset - first));
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn map_page(&mut self, region: &Region, page: u64, offset: u64) -> Result<(), VmmError> {
+        let frame = match region.backing {
+            Backing::Allocated => GlobalFrameAllocator
+                .allocate_frame()
+                .ok_or(VmmError::OutOfMemory)?,
+            Backing::Physical(phys) => PhysFrame::containing_address(phys + offset),
+            Backing::Existing => unreachable!("existing regions are already mapped"),
+        };
+        // keep the upper levels permissive, so that `protect` only has to
+        // change the last level.
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let result = unsafe {
+            self.page_table.map_to(
+                page,
+                frame,
+                region.flags | PageTableFlags::PRESENT,
+                table_flags,
+                &mut GlobalFrameAllocator,
+            )
+        };
+        if result.is_err() && region.backing == Backing::Allocated {
+            frame::free_frame(frame);
+        }
+        Ok(result?)
+    }
+
+    fn unmap_pages(&mut self, region: &Region, pages: impl Iterator<Item = u64>) {
+        for page in pages {
+            match self.page_table.unmap(page) {
+                Ok(frame) => {
+                    if region.backing == Backing::Allocated {
+                        frame::free_frame(frame);
+                    }
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(error) => panic!("failed to unmap {page:#x} of {}: {error:?}", region.name),
+            }
+        }
+    }
+
+    fn insert(&mut self, region: Region) -> Result<Region, VmmError> {
+        let slot = self.check_free(region.start, region.size)?;
+        if region.backing != Backing::Existing {
+            self.map_pages(&region, 0)?;
+        }
+        self.regions[slot] = Some(region);
+        Ok(region)
+    }
+
+    /// Map `size` bytes of newly allocated memory at `start`.
+    pub fn map(
+        &mut self,
+        name: &'static str,
+        start: u64,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmmError> {
+        self.insert(Region {
+            name,
+            start,
+            size,
+            flags,
+            backing: Backing::Allocated,
+        })
+    }
+
+    /// Map `size` bytes of newly allocated memory at the lowest address in
+    /// `area` that is aligned to `align` and not taken by another region.
+    pub fn map_in(
+        &mut self,
+        name: &'static str,
+        area: Range<u64>,
+        size: u64,
+        align: u64,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmmError> {
+        let mut start = area.start.next_multiple_of(align);
+        // the regions aren't sorted, so skip past whichever one is in the
+        // way until none is.
+        while let Some(region) = self.regions().find(|r| r.overlaps(start, size)) {
+            start = region.end().next_multiple_of(align);
+        }
+        if start + size > area.end {
+            return Err(VmmError::OutOfMemory);
+        }
+        self.map(name, start, size, flags)
+    }
+
+    /// Map `size` bytes of physical memory at `phys` to `start`.
+    pub fn map_physical(
+        &mut self,
+        name: &'static str,
+        start: u64,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmmError> {
+        if !phys.is_aligned(PAGE_SIZE) {
+            return Err(VmmError::Unaligned);
+        }
+        self.insert(Region {
+            name,
+            start,
+            size,
+            flags,
+            backing: Backing::Physical(phys),
+        })
+    }
+
+    /// Keep track of memory that is mapped already, rounded out to whole
+    /// pages.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        start: u64,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<Region, VmmError> {
+        let aligned = start / PAGE_SIZE * PAGE_SIZE;
+        let region = Region {
+            name,
+            start: aligned,
+            size: (start + size).next_multiple_of(PAGE_SIZE) - aligned,
+            flags,
+            backing: Backing::Existing,
+        };
+        if region
+            .pages()
+            .any(|page| self.page_table.translate(page).is_none())
+        {
+            return Err(VmmError::NotMapped);
+        }
+        self.insert(region)
+    }
+
+    /// Map `by` more bytes right after the end of the region at `start`.
+    pub fn extend(&mut self, start: u64, by: u64) -> Result<Region, VmmError> {
+        let idx = self.region_index(start)?;
+        let region = self.regions[idx].unwrap();
+        if region.backing != Backing::Allocated {
+            return Err(VmmError::NoRegion);
+        }
+        check_range(region.end(), by)?;
+        if let Some(other) = self.regions().find(|r| r.overlaps(region.end(), by)) {
+            return Err(VmmError::Overlaps(other.name));
+        }
+
+        let grown = Region {
+            size: region.size + by,
+            ..region
+        };
+        self.map_pages(&grown, region.size)?;
+        self.regions[idx] = Some(grown);
+        Ok(grown)
+    }
+
+    /// Unmap the region at `start`, freeing its frames if we allocated them.
+    pub fn unmap(&mut self, start: u64) -> Result<Region, VmmError> {
+        let idx = self.region_index(start)?;
+        let region = self.regions[idx].take().unwrap();
+        if region.backing != Backing::Existing {
+            self.unmap_pages(&region, region.pages());
+        }
+        Ok(region)
+    }
+
+    /// Change the flags of the pages in `start..start + size`, which have to
+    /// be inside one region.
+    pub fn protect(
+        &mut self,
+        start: u64,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), VmmError> {
+        check_range(start, size)?;
+        let idx = self
+            .regions
+            .iter()
+            .position(|r| r.is_some_and(|r| r.start <= start && start + size <= r.end()))
+            .ok_or(VmmError::NoRegion)?;
+
+        let flags = flags | PageTableFlags::PRESENT;
+        for page in page_range(start, size) {
+            if let Err(error) = unsafe { self.page_table.update_flags(page, flags) } {
+                panic!("failed to protect {page:#x}: {error:?}");
+            }
+        }
+
+        let region = self.regions[idx].as_mut().unwrap();
+        if region.start == start && region.size == size {
+            region.flags = flags;
+        }
+        Ok(())
+    }
+}
+
+static KERNEL_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+/// Start managing the address space of `page_table`.
+pub fn init(page_table: PageTables) {
+    without_interrupts(|| *KERNEL_SPACE.lock() = Some(AddressSpace::new(page_table)));
+}
+
+/// Run `f` with the kernel address space.
+pub fn with_address_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+    without_interrupts(|| f(KERNEL_SPACE.lock().as_mut().expect("vmm not initialized")))
+}
+
+pub fn map(
+    name: &'static str,
+    start: u64,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    with_address_space(|space| space.map(name, start, size, flags))
+}
+
+pub fn map_in(
+    name: &'static str,
+    area: Range<u64>,
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    with_address_space(|space| space.map_in(name, area, size, align, flags))
+}
+
+pub fn map_physical(
+    name: &'static str,
+    start: u64,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    with_address_space(|space| space.map_physical(name, start, phys, size, flags))
+}
+
+pub fn register(
+    name: &'static str,
+    start: u64,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    with_address_space(|space| space.register(name, start, size, flags))
+}
+
+pub fn extend(start: u64, by: u64) -> Result<Region, VmmError> {
+    with_address_space(|space| space.extend(start, by))
+}
+
+pub fn unmap(start: u64) -> Result<Region, VmmError> {
+    with_address_space(|space| space.unmap(start))
+}
+
+// nothing changes the permissions of a mapping yet.
+#[allow(dead_code)]
+pub fn protect(start: u64, size: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+    with_address_space(|space| space.protect(start, size, flags))
+}
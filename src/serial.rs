@@ -30,6 +30,51 @@ pub fn _print(args: ::core::fmt::Arguments) {
     })
 }
 
+/// Call `handler` with every byte that arrives on the serial port, from its
+/// interrupt handler. The interrupt controller must be set up.
+pub fn on_receive(handler: fn(u8)) {
+    const COM1_IRQ: u8 = 4;
+    let vector = crate::interrupts::controller::enable_isa_irq(COM1_IRQ, "serial", move |_| {
+        loop {
+            // don't hold the lock while `handler` runs, it may print.
+            let Ok(byte) = serial1().try_receive() else {
+                break;
+            };
+            handler(byte);
+        }
+    });
+    if vector.is_none() {
+        log::warn!("serial: no vector for IRQ {COM1_IRQ}, ignoring input");
+    }
+}
+
+struct SerialLogger;
+
+impl log::Log for SerialLogger {
+    fn enabled(&self, _metadata: &log::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        crate::sprintln!(
+            "[{:<5} {}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: SerialLogger = SerialLogger;
+
+/// Send everything logged through the `log` crate to the serial port.
+pub fn init_logger() {
+    log::set_logger(&LOGGER).expect("logger already initialized");
+    log::set_max_level(log::LevelFilter::Info);
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! sprint {
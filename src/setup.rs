@@ -1,36 +1,42 @@
 use limine::BaseRevision;
 use limine::paging::Mode;
 use limine::request::{
-    FramebufferRequest, HhdmRequest, MemoryMapRequest, PagingModeRequest, RequestsEndMarker,
-    RequestsStartMarker, RsdpRequest, StackSizeRequest,
+    FramebufferRequest, HhdmRequest, KernelAddressRequest, MemoryMapRequest, PagingModeRequest,
+    RequestsEndMarker, RequestsStartMarker, RsdpRequest, StackSizeRequest,
 };
-use x86_64::VirtAddr;
 
+use crate::mem::kernel::KernelAddress;
+use crate::mem::mmio::{self, CacheMode};
 use crate::time::Mapper;
 use crate::time;
 
-pub fn init() {
+/// Set up memory management, which everything else needs.
+pub fn init_memory() {
+    crate::serial::init_logger();
     let physical_memory_offset = HHDM_REQUEST.get_response().unwrap().offset();
     unsafe {
         crate::mem::init(
-            VirtAddr::new(physical_memory_offset),
+            physical_memory_offset,
+            PAGING_MODE_REQUEST.get_response().map(|r| r.mode()),
+            KERNEL_ADDRESS_REQUEST
+                .get_response()
+                .map(|r| KernelAddress {
+                    physical_base: r.physical_base(),
+                    virtual_base: r.virtual_base(),
+                })
+                .unwrap(),
             MEMORY_MAP_REQUEST.get_response().unwrap().entries(),
         )
     };
-    crate::interrupts::init();
-    let physical_memory_offset = physical_memory_offset as usize;
-    let mapper = Mapper::new(physical_memory_offset);
-    let tables = time::get_acpi_tables(
-        RSDP_REQUEST.get_response().unwrap().address() as usize - physical_memory_offset,
-        mapper,
-    );
-    let platform_info = time::get_platform_info(&tables);
-    time::init_and_disable_old_pic();
-    time::init_lapic(&platform_info, &mapper);
-    let (ioapic, pitreg) = time::init_ioapic(&platform_info, &mapper);
-    time::init(ioapic, pitreg);
-    x86_64::instructions::interrupts::enable();
+    #[cfg(feature = "alloc-bench")]
+    crate::mem::slab::benchmark();
+}
 
+pub fn init() {
+    // the responses are in bootloader reclaimable memory, which is reclaimed
+    // at the end, so this is the last time we look at them.
+    let physical_memory_offset = HHDM_REQUEST.get_response().unwrap().offset() as usize;
+    let rsdp_address = RSDP_REQUEST.get_response().unwrap().address() as usize;
     let frame_buffer = FRAMEBUFFER_REQUEST
         .get_response()
         .unwrap()
@@ -38,11 +44,45 @@ pub fn init() {
         .next()
         .unwrap();
 
-    crate::draw::init(crate::draw::FrameBufferManager::new(&frame_buffer));
+    crate::interrupts::init();
+    let tables = time::get_acpi_tables(rsdp_address - physical_memory_offset, Mapper);
+    let platform_info = time::get_platform_info(&tables);
+    crate::interrupts::controller::init(&platform_info);
+    time::hpet::init(&tables);
+    time::init();
+    time::rtc::init(&tables);
+    time::rtc::enable_update_irq();
+    x86_64::instructions::interrupts::enable();
+    #[cfg(feature = "delay-test")]
+    time::delay_test();
+
+    // the bootloader gives us the framebuffer in the higher half direct map.
+    let frame_buffer_ptr = mmio::map(
+        "framebuffer",
+        frame_buffer.addr() as u64 - physical_memory_offset as u64,
+        frame_buffer.pitch() * frame_buffer.height(),
+        CacheMode::WriteCombining,
+    )
+    .expect("failed to map the framebuffer");
+
+    crate::draw::init(crate::draw::FrameBufferManager::new(
+        &frame_buffer,
+        frame_buffer_ptr,
+    ));
+
+    crate::serial::on_receive(|byte| {
+        if byte == b'm' {
+            crate::mem::report::request();
+        }
+    });
+
+    // we run on our own stack and page tables, and have everything we need
+    // from the responses and the memory map.
+    unsafe { crate::mem::frame::reclaim_bootloader_memory() };
 }
 
 // 32 KiB of stack
-const STACK_SIZE: u64 = 32 * 1024;
+pub const STACK_SIZE: u64 = 32 * 1024;
 
 /// Sets the base revision to the latest revision supported by the crate.
 /// See specification for further info.
@@ -73,6 +113,10 @@ static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 #[link_section = ".requests"]
 static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 
+#[used]
+#[link_section = ".requests"]
+static KERNEL_ADDRESS_REQUEST: KernelAddressRequest = KernelAddressRequest::new();
+
 #[used]
 #[link_section = ".requests"]
 static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
@@ -88,6 +132,7 @@ static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 #[panic_handler]
 #[cfg(not(test))]
 pub fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    crate::interrupts::nmi::panic_all_cpus();
     crate::sprintln!("{}", info);
     crate::utils::hlt_loop()
 }
@@ -2,41 +2,36 @@
 use alloc::alloc::Global;
 use core::num::Wrapping;
 use core::ptr::NonNull;
-use core::sync::atomic::AtomicU32;
 use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicU32, AtomicU64};
 use core::time::Duration;
 
 use acpi::{AcpiTables, InterruptModel, PlatformInfo};
-use pic8259::ChainedPics;
-use x86_64::instructions::port::Port;
-use x86_64::instructions::{hlt, interrupts};
+use x86_64::instructions::interrupts;
 
-use super::interrupts::{InterruptIndex, PIC_1_OFFSET, PIC_2_OFFSET};
+use super::interrupts::controller::{self, Controller};
+use super::interrupts::{InterruptIndex, ioapic};
+use crate::mem::mmio::{self, CacheMode};
 use crate::sprintln;
+use crate::utils::rdtsc;
 
-#[derive(Clone, Copy)]
-pub struct Mapper {
-    pub physical_memory_offset: usize,
-}
-
-impl Mapper {
-    #[inline]
-    pub fn new(physical_memory_offset: usize) -> Self {
-        Self {
-            physical_memory_offset,
-        }
-    }
+pub mod clockevent;
+pub mod hpet;
+pub mod instant;
+pub mod pit;
+pub mod rtc;
+mod selftest;
+pub mod wheel;
 
-    #[inline]
-    pub fn phys_to_virt(&self, phys: usize) -> usize {
-        self.physical_memory_offset + phys
-    }
+pub use self::instant::{Instant, now};
+pub use self::wheel::sleep;
 
-    #[inline]
-    pub fn phys_to_virt_ptr(&self, phys: usize) -> NonNull<u8> {
-        NonNull::new(self.phys_to_virt(phys) as *mut u8).unwrap()
-    }
-}
+/// Maps ACPI tables for the `acpi` crate.
+///
+/// The tables may be in reserved memory, which isn't in the direct map, so
+/// each one is mapped while it is in use.
+#[derive(Clone, Copy)]
+pub struct Mapper;
 
 impl acpi::AcpiHandler for Mapper {
     unsafe fn map_physical_region<T>(
@@ -44,17 +39,19 @@ impl acpi::AcpiHandler for Mapper {
         physical_address: usize,
         size: usize,
     ) -> acpi::PhysicalMapping<Self, T> {
-        acpi::PhysicalMapping::new(
-            physical_address,
-            NonNull::new_unchecked((self.physical_memory_offset + physical_address) as *mut _),
-            size,
-            size,
-            *self,
+        let virt = mmio::map(
+            "acpi",
+            physical_address as u64,
+            size as u64,
+            CacheMode::WriteBack,
         )
+        .expect("failed to map ACPI tables");
+        acpi::PhysicalMapping::new(physical_address, virt.cast(), size, size, *self)
     }
 
-    // No-op since we don't remove entries.
-    fn unmap_physical_region<T>(_: &acpi::PhysicalMapping<Self, T>) {}
+    fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
+        mmio::unmap(region.virtual_start().cast()).expect("failed to unmap ACPI tables");
+    }
 }
 
 pub type Tables = AcpiTables<Mapper>;
@@ -75,6 +72,11 @@ pub fn lapic() -> Lapic {
     unsafe { LAPIC.unwrap_unchecked() }
 }
 
+/// Like `lapic`, but returns `None` if the LAPIC hasn't been initialized yet.
+pub fn try_lapic() -> Option<Lapic> {
+    unsafe { LAPIC }
+}
+
 macro_rules! common_apic_methods {
     ($offset:ident) => {
         #[inline]
@@ -103,6 +105,17 @@ pub const APIC_MASKED: u32 = 0x10000;
 ////////////////////////////////////
 // REGISTERS
 
+/// The local APIC ID, in bits 24 to 31.
+pub const LAPIC_ID_REG: usize = 0x20;
+
+/// The Spurious Interrupt Vector Register.
+///
+/// Bits 0 to 7 are the spurious vector, bit 8 enables the APIC.
+pub const LAPIC_SVR_REG: usize = 0xF0;
+
+/// The Error Status Register. Must be written to before reading it.
+pub const LAPIC_ESR_REG: usize = 0x280;
+
 /// The local vector table for LAPIC timer.
 ///
 /// See LVT format at https://wiki.osdev.org/APIC#Local_Vector_Table_Registers
@@ -112,6 +125,9 @@ pub const LAPIC_LVT_LINT0_REG: usize = 0x350;
 
 pub const LAPIC_LVT_LINT1_REG: usize = 0x360;
 
+/// The local vector table entry for internal APIC errors.
+pub const LAPIC_LVT_ERROR_REG: usize = 0x370;
+
 /// The initial count of the timer.
 pub const LAPIC_TIMER_INITCNT_REG: usize = 0x380;
 
@@ -121,6 +137,21 @@ pub const LAPIC_TIMER_CURRCNT_REG: usize = 0x390;
 /// The divider of the timer.
 pub const LAPIC_TIMER_DIV_REG: usize = 0x3E0;
 
+/// Interrupt command register, low 32 bits. Writing to it sends the IPI.
+pub const LAPIC_ICR_LOW_REG: usize = 0x300;
+
+/// Interrupt command register, high 32 bits (the destination).
+pub const LAPIC_ICR_HIGH_REG: usize = 0x310;
+
+/// ICR delivery mode NMI.
+pub const APIC_ICR_NMI: u32 = 0b100 << 8;
+
+/// ICR delivery status, set while the IPI has not been accepted yet.
+pub const APIC_ICR_PENDING: u32 = 1 << 12;
+
+/// ICR destination shorthand "all excluding self".
+pub const APIC_ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
 /// Local APIC.
 #[derive(Clone, Copy)]
 pub struct Lapic {
@@ -138,6 +169,31 @@ impl Lapic {
         self.start_ptr.as_ptr().add(offset).cast()
     }
 
+    /// The ID of the processor this LAPIC belongs to.
+    #[inline]
+    pub unsafe fn id(&mut self) -> u32 {
+        self.read_register(LAPIC_ID_REG) >> 24
+    }
+
+    /// Latch and read the Error Status Register.
+    pub unsafe fn error_status(&mut self) -> u32 {
+        // the write updates the register with the errors since the last write.
+        self.write_register(LAPIC_ESR_REG, 0);
+        self.read_register(LAPIC_ESR_REG)
+    }
+
+    /// Send an NMI to every other processor.
+    pub unsafe fn send_nmi_all_excluding_self(&mut self) {
+        self.write_register(LAPIC_ICR_HIGH_REG, 0);
+        self.write_register(
+            LAPIC_ICR_LOW_REG,
+            APIC_ICR_NMI | APIC_ICR_ALL_EXCLUDING_SELF,
+        );
+        while self.read_register(LAPIC_ICR_LOW_REG) & APIC_ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
     common_apic_methods!(usize);
 }
 
@@ -162,19 +218,12 @@ impl IoApic {
     common_apic_methods!(u8);
 }
 
-pub const IOAPICVER: u8 = 1;
-
-/// Initialize and disable the old PIC.
-pub fn init_and_disable_old_pic() {
-    let mut chained_pics = unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) };
+// SAFETY: the pointer is to MMIO registers, which any processor can access.
+unsafe impl Send for IoApic {}
 
-    unsafe {
-        chained_pics.initialize();
-        chained_pics.disable();
-    }
-}
+pub const IOAPICVER: u8 = 1;
 
-pub fn init_lapic(platform_info: &PlatformInfo<'_, Global>, mapper: &Mapper) {
+pub fn init_lapic(platform_info: &PlatformInfo<'_, Global>) {
     let apic = match &platform_info.interrupt_model {
         InterruptModel::Apic(apic) => apic,
         _ => panic!("unknown interrupt model"),
@@ -182,12 +231,22 @@ pub fn init_lapic(platform_info: &PlatformInfo<'_, Global>, mapper: &Mapper) {
 
     let lapic_addr = apic.local_apic_address;
 
-    let start_ptr = mapper.phys_to_virt_ptr(lapic_addr as usize);
+    let start_ptr = mmio::map("lapic", lapic_addr, 4096, CacheMode::Uncacheable)
+        .expect("failed to map the LAPIC");
     let mut lapic = Lapic { start_ptr };
 
-    // Set the Spurious Interrupt Vector Register bit 8 to start receiving interrupts.
+    // Set the Spurious Interrupt Vector Register bit 8 to start receiving interrupts,
+    // and make spurious interrupts arrive at a vector we have a handler for.
     unsafe {
-        lapic.update_register(0xF0, |reg| reg | 0x100);
+        lapic.update_register(LAPIC_SVR_REG, |reg| {
+            reg & !0xFF | 0x100 | InterruptIndex::Spurious as u32
+        });
+    }
+
+    // report errors detected by the APIC, and clear the ones that happened before.
+    unsafe {
+        lapic.write_register(LAPIC_LVT_ERROR_REG, InterruptIndex::LapicError as u32);
+        lapic.error_status();
     }
 
     unsafe {
@@ -205,75 +264,6 @@ pub fn init_lapic(platform_info: &PlatformInfo<'_, Global>, mapper: &Mapper) {
     }
 }
 
-/// Initialize the I/O APIC to enable PIT interrupts.
-///
-/// Returns the I/O APIC wit the PIT register.
-pub fn init_ioapic(platform_info: &PlatformInfo<'_, Global>, mapper: &Mapper) -> (IoApic, u8) {
-    let apic = match &platform_info.interrupt_model {
-        InterruptModel::Apic(apic) => apic,
-        _ => panic!("unknown interrupt model"),
-    };
-
-    // find IRQ0 which is emitted by the PIT.
-    let pit = apic
-        .interrupt_source_overrides
-        .iter()
-        .find(|ov| ov.isa_source == 0x0)
-        .expect("no PIT");
-    let overriden_index = pit.global_system_interrupt;
-
-    // find the I/O APIC that handles IRQ0.
-    let mut found_reg = None;
-    for io_apic in apic.io_apics.iter() {
-        let address = io_apic.address;
-        let base = io_apic.global_system_interrupt_base;
-
-        let mut ioapic = IoApic {
-            start_ptr: mapper.phys_to_virt_ptr(address as usize),
-        };
-
-        let ioapicver = unsafe { ioapic.read_register(IOAPICVER) };
-
-        // https://wiki.osdev.org/IOAPIC#IOAPICVER
-        let max_redir_count = (ioapicver >> 16) as u8 + 1;
-
-        let mut bsp_acpi_id = platform_info
-            .processor_info
-            .as_ref()
-            .expect("apic proc info")
-            .boot_processor
-            .local_apic_id;
-        // the correct place for destination.
-        bsp_acpi_id <<= 56 - 32;
-
-        for idx in 0..max_redir_count {
-            // https://wiki.osdev.org/IOAPIC#IOREDTBL
-            let reg = 0x10 + idx * 2;
-            if idx as u32 + base == overriden_index {
-                let vector = InterruptIndex::Timer.as_u8() as u32;
-
-                // The default flags for the register are all zeros. The only interesting bits are the vector bits.
-                unsafe {
-                    ioapic.write_register(reg, vector);
-                }
-
-                // set the processor to send interrupts to. In this case it is the bootstrap processor.
-                unsafe {
-                    ioapic.write_register(reg + 1, bsp_acpi_id);
-                }
-
-                found_reg = Some((ioapic, reg));
-            } else {
-                // Per https://wiki.osdev.org/APIC#IO_APIC_Registers, set the "masked" flag
-                // of other redir entries
-                unsafe { ioapic.update_register(reg, |v| v | 1 << 16) }
-            }
-        }
-    }
-
-    found_reg.expect("could not find redirected PIT IRQ")
-}
-
 /// number of APIC ticks in 10ms, used by AP init sequence.
 ///
 /// Note that this is NOT the number of IRQs per 10ms.
@@ -287,76 +277,213 @@ fn get_irq_cnt() -> Wrapping<usize> {
     unsafe { (&raw mut TIMER).read_volatile() }
 }
 
-/// Configure the programmable interval timer for transition to
-/// the Local APIC timer. Interrupts must not be enabled.
-fn calibrate_apic_timer(mut ioapic: IoApic, pitreg: u8) {
-    // set a divider for 100Hz which is 10ms per IRQ from the PIT.
-    let divider = 11932u16;
+/// How many PIT periods to wait for its IRQ before giving up.
+const PIT_IRQ_TIMEOUT_PERIODS: usize = 5;
 
-    // configure the PIT to send an IRQ every 10ms.
-    let mut channel0 = Port::new(0x40);
-    unsafe {
-        // select channel 0, access mode lobyte/hibyte, mode 2 rate generator
-        Port::new(0x43).write(0b00110100u8);
+/// Wait for the IRQ counter to change from `since`, returns the new count.
+///
+/// Returns `None` if no PIT IRQ arrived for a few periods of the PIT, which
+/// has to be running in periodic mode.
+fn wait_for_pit_irq(since: Wrapping<usize>) -> Option<Wrapping<usize>> {
+    let mut periods = 0;
+    let mut last = pit::read_count();
+    loop {
+        let curr = get_irq_cnt();
+        if curr != since {
+            return Some(curr);
+        }
 
-        // send the lo/hi bytes to set the reload value.
-        channel0.write(divider as u8);
-        channel0.write((divider >> 8) as u8);
+        // the count going up means that it was reloaded.
+        let count = pit::read_count();
+        if count > last {
+            periods += 1;
+            if periods > PIT_IRQ_TIMEOUT_PERIODS {
+                return None;
+            }
+        }
+        last = count;
     }
+}
 
-    let mut lapic = lapic();
+/// LAPIC timer ticks and TSC cycles counted over some time.
+#[derive(Clone, Copy)]
+struct Measurement {
+    /// 0 without a LAPIC.
+    lapic_ticks: u32,
+    tsc_cycles: u64,
+}
 
-    // prepare LAPIC timer
-    unsafe {
-        // there are other flags the lvt register allows configuring.
-        // for now set the interrrupt vector, all other flags are irrelevant.
-        //
-        // the timer will be in one-shot mode, meaning it will start decrementing
-        // the count value when we set an init count.
-        lapic.write_register(LAPIC_LVT_TIMER_REG, InterruptIndex::ScratchTimer as u32);
-        // set the divide value to 16.
-        lapic.write_register(LAPIC_TIMER_DIV_REG, 3);
+/// Start the LAPIC timer counting down from its maximum, returns the TSC.
+fn start_measurement(lapic: Option<Lapic>) -> u64 {
+    if let Some(mut lapic) = lapic {
+        unsafe {
+            lapic.write_register(LAPIC_TIMER_INITCNT_REG, u32::MAX);
+        }
     }
+    rdtsc()
+}
 
-    // enable interrupts
-    interrupts::enable();
+/// Stop the LAPIC timer and measure how much it and the TSC advanced since
+/// `start_measurement` returned `tsc_start`.
+fn finish_measurement(lapic: Option<Lapic>, tsc_start: u64) -> Measurement {
+    let tsc_cycles = rdtsc() - tsc_start;
+    let lapic_ticks = lapic.map_or(0, |mut lapic| unsafe {
+        let ticks = u32::MAX - lapic.read_register(LAPIC_TIMER_CURRCNT_REG);
+        // Stop the APIC timer
+        lapic.write_register(LAPIC_TIMER_INITCNT_REG, 0);
+        ticks
+    });
+    Measurement {
+        lapic_ticks,
+        tsc_cycles,
+    }
+}
 
+/// Measure between two PIT IRQs, which must arrive at `InterruptIndex::Timer`.
+/// Interrupts must be enabled.
+fn measure_with_pit_irq(lapic: Lapic) -> Option<Measurement> {
     // we need to wait until PIT interrupts so the delay is as accurate as possible
-    let saved_pit_cnt = get_irq_cnt();
-    let mut curr_pit_cnt;
+    let curr_pit_cnt = wait_for_pit_irq(get_irq_cnt())?;
 
-    loop {
-        curr_pit_cnt = get_irq_cnt();
+    // PIT just emitted IRQ, start LAPIC timer.
+    let tsc_start = start_measurement(Some(lapic));
+
+    // wait for another IRQ from the PIT.
+    let irq = wait_for_pit_irq(curr_pit_cnt);
+    let measurement = finish_measurement(Some(lapic), tsc_start);
+    irq.map(|_| measurement)
+}
 
-        if curr_pit_cnt - saved_pit_cnt >= Wrapping(1) {
-            break;
+/// Calibrate with the IRQs of PIT channel 0.
+///
+/// Without an interrupt source override, the PIT is normally identity mapped
+/// to GSI 0, but plenty of chipsets wire it to GSI 2 instead without telling
+/// us, so we try both.
+fn calibrate_with_pit_irq(mut lapic: Lapic) -> Option<Measurement> {
+    pit::start_periodic();
+
+    let route = ioapic::isa_route(0);
+    let candidates: &[u32] = if route.gsi == 0 {
+        &[0, 2]
+    } else {
+        &[route.gsi]
+    };
+
+    let id = unsafe { lapic.id() };
+    for &gsi in candidates {
+        // send the PIT's IRQs to this processor.
+        ioapic::route_gsi(
+            gsi,
+            InterruptIndex::Timer.as_u8(),
+            id,
+            route.active_low,
+            route.level_triggered,
+        );
+
+        interrupts::enable();
+        let measurement = measure_with_pit_irq(lapic);
+        interrupts::disable();
+
+        // mask the PIT I/O APIC entry.
+        ioapic::mask_gsi(gsi);
+
+        if measurement.is_some() {
+            return measurement;
         }
+        log::warn!("no IRQs from the PIT on GSI {gsi}");
     }
 
-    // PIT just emitted IRQ, start LAPIC timer.
-    unsafe {
-        lapic.write_register(LAPIC_TIMER_INITCNT_REG, u32::MAX);
+    None
+}
+
+/// Calibrate by polling PIT channel 2, which doesn't need any interrupts.
+fn calibrate_with_pit_polled(lapic: Option<Lapic>) -> Measurement {
+    let mut tsc_start = 0;
+    pit::wait_10ms_polled(|| tsc_start = start_measurement(lapic));
+    finish_measurement(lapic, tsc_start)
+}
+
+/// How long to measure against the HPET. Longer than the PIT's 10ms since
+/// reading the HPET is cheap and the result is more accurate.
+const HPET_CALIBRATION_MS: u32 = 50;
+
+/// Calibrate by polling the HPET's main counter for `ms`, a multiple of 10.
+fn calibrate_with_hpet(lapic: Option<Lapic>, ms: u32) -> Measurement {
+    let mut tsc_start = 0;
+    hpet::wait_polled(Duration::from_millis(ms.into()), || {
+        tsc_start = start_measurement(lapic)
+    });
+    let measurement = finish_measurement(lapic, tsc_start);
+
+    // scale down to 10ms.
+    Measurement {
+        lapic_ticks: measurement.lapic_ticks / (ms / 10),
+        tsc_cycles: measurement.tsc_cycles / (ms / 10) as u64,
+    }
+}
+
+/// Measure the LAPIC timer (if any) and the TSC over 10ms, using the most
+/// accurate reference we have, and cross-check the result.
+fn calibrate(lapic: Option<Lapic>) -> Measurement {
+    let measurement = calibrate_once(lapic);
+    selftest::run(lapic, measurement)
+}
+
+/// A single measurement against the best reference.
+fn calibrate_once(lapic: Option<Lapic>) -> Measurement {
+    if hpet::available() {
+        return calibrate_with_hpet(lapic, HPET_CALIBRATION_MS);
     }
 
-    // wait for another IRQ from the PIT.
-    while get_irq_cnt() - curr_pit_cnt < Wrapping(1) {}
+    if let Some(lapic) = lapic {
+        if let Some(measurement) = calibrate_with_pit_irq(lapic) {
+            return measurement;
+        }
+        log::warn!("no IRQs from the PIT, polling PIT channel 2 instead");
+    }
+
+    calibrate_with_pit_polled(lapic)
+}
+
+/// TSC frequency in Hz, 0 until calibrated.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// The calibrated frequency of the time stamp counter in Hz.
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Relaxed)
+}
+
+fn store_tsc_frequency(measurement: Measurement) {
+    let tsc_hz = measurement.tsc_cycles * 100;
+    sprintln!("tsc frequency = {} kHz", tsc_hz / 1000);
+    TSC_HZ.store(tsc_hz, Relaxed);
+}
 
-    // Stop the APIC timer
+/// Configure the programmable interval timer for transition to
+/// the Local APIC timer. Interrupts must not be enabled.
+fn calibrate_apic_timer() {
+    let mut lapic = lapic();
+
+    // prepare LAPIC timer
     unsafe {
-        lapic.write_register(LAPIC_LVT_TIMER_REG, APIC_MASKED);
+        // there are other flags the lvt register allows configuring.
+        // for now set the interrrupt vector, all other flags are irrelevant.
+        //
+        // the timer will be in one-shot mode, meaning it will start decrementing
+        // the count value when we set an init count.
+        lapic.write_register(LAPIC_LVT_TIMER_REG, InterruptIndex::ScratchTimer as u32);
+        // set the divide value to 16.
+        lapic.write_register(LAPIC_TIMER_DIV_REG, 3);
     }
 
     // we've now measured the number of LAPIC ticks in 10ms.
-    let apic_ticks_in_10ms = u32::MAX - unsafe { lapic.read_register(LAPIC_TIMER_CURRCNT_REG) };
-
-    interrupts::disable();
+    let measurement = calibrate(Some(lapic));
+    let apic_ticks_in_10ms = measurement.lapic_ticks;
 
     sprintln!("apic ticks in 10ms = {apic_ticks_in_10ms}");
 
     APIC_TICKS_IN_10MS.store(apic_ticks_in_10ms, Relaxed);
-
-    // mask the PIT I/O APIC entry.
-    unsafe { ioapic.write_register(pitreg, APIC_MASKED) }
+    store_tsc_frequency(measurement);
 
     // configure the lapic timer to send an IRQ per 10ms periodically.
     unsafe {
@@ -372,60 +499,143 @@ fn calibrate_apic_timer(mut ioapic: IoApic, pitreg: u8) {
     }
 }
 
-/// Configure to have 100 Timer IRQs per second, i.e. 1 IRQ per 10ms.
+/// Configure to have 100 Timer IRQs per second, i.e. 1 IRQ per 10ms, at least
+/// at first.
 ///
 /// Interrupts should not be enabled but should be properly configured
 /// before calling this. Interrupts will not be enabled when this function
 /// returns.
 ///
-/// The programmable interval timer (PIT) should be configured to IRQ at
-/// `InterruptIndex::Timer`. We currently use it to calibrate the APIC timer.
-pub fn init(ioapic: IoApic, pitreg: u8) {
-    sprintln!("im currently here1");
-    calibrate_apic_timer(ioapic, pitreg);
-    sprintln!("im currently here2");
+/// The interrupt controller and, if there is one, the HPET must be
+/// initialized. With the APIC, we calibrate the APIC timer against the HPET,
+/// or against the programmable interval timer (PIT), using its IRQs if they
+/// arrive and polling it otherwise. With the legacy PICs, the PIT itself is
+/// the timer and its IRQ 0 already arrives at `InterruptIndex::Timer`.
+///
+/// The TSC is calibrated along the way, and the clock behind `now` is
+/// picked afterwards. If that clock doesn't depend on the timer IRQs, the
+/// LAPIC timer then stops being periodic, see `clockevent`.
+pub fn init() {
+    match controller::controller() {
+        Controller::Apic => {
+            sprintln!("im currently here1");
+            calibrate_apic_timer();
+            sprintln!("im currently here2");
+        }
+        Controller::Pic => {
+            store_tsc_frequency(calibrate(None));
+            pit::start_periodic();
+            controller::unmask_isa_irq(0);
+        }
+    }
+    instant::init();
+    clockevent::init();
+    wheel::init();
+    if hpet::available() {
+        hpet::start_wrap_timer();
+    }
 }
 
-/// precision microsecond delay, `micros` should not be larger than 1000.
-pub fn udelay(micros: usize) {
-    // instead of using the IRQ counter, we need to read the current count
-    // register directly.
-    let ticks_per_10ms = APIC_TICKS_IN_10MS.load(Relaxed) as usize;
-    let delay_ticks = ticks_per_10ms * micros / 10000;
-
-    let get_tick = || unsafe { lapic().read_register(LAPIC_TIMER_CURRCNT_REG) } as usize;
+/// Busy-wait until a periodic down-counter that is reloaded with `period`
+/// and raises the timer IRQ on every reload has counted down `ticks`.
+///
+/// Reloads are noticed by the count going up, which only works if we read
+/// it at least once per period, so long interrupt handlers could make us
+/// miss some. The IRQ counter catches those, but may include one IRQ that
+/// was still pending from before we started. Taking the larger of both
+/// never overestimates the elapsed time, so we never return early.
+fn countdown_delay(ticks: u64, period: u64, read_count: impl Fn() -> u64) {
+    // read both in a way that is consistent with each other.
+    let position = || loop {
+        let irqs = get_irq_cnt();
+        let count = read_count();
+        if get_irq_cnt() == irqs {
+            return (irqs, count);
+        }
+    };
 
-    let current_tick = get_tick();
+    let (start_irqs, start_count) = position();
+    let mut last_count = start_count;
+    let mut wraps = 0;
+    loop {
+        let (irqs, count) = position();
+        if count > last_count {
+            wraps += 1;
+        }
+        last_count = count;
 
-    // not hlt-ing here, as the duration is smaller than 1ms
-    //
-    // N.B. the orders are swapped here because the LAPIC timer decrements
-    // the tick counter while our kernel IRQ handler increments the counter.
-    while current_tick - get_tick() < delay_ticks {}
+        let reloads = wraps.max(((irqs - start_irqs).0 as u64).saturating_sub(1));
+        if reloads * period + start_count - count >= ticks {
+            return;
+        }
+        core::hint::spin_loop();
+    }
 }
 
-/// precision millis delay
-fn mdelay(mut millis: usize) {
-    while millis >= 10 {
-        let curr_irq_cnt = get_irq_cnt();
+/// precision microsecond delay.
+///
+/// Doesn't depend on interrupts being enabled, but with the PICs or a
+/// periodic LAPIC timer, interrupt handlers running for longer than 10ms
+/// may make it take longer.
+pub fn udelay(micros: usize) {
+    let micros = micros as u64;
+
+    // the LAPIC timer only counts down until the next deadline, but then
+    // we have a clock that works without it.
+    if clockevent::mode() != clockevent::Mode::Periodic {
+        let deadline = Instant::now() + Duration::from_micros(micros);
+        while Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    }
 
-        while get_irq_cnt() - curr_irq_cnt < Wrapping(1) {
-            hlt();
+    match controller::controller() {
+        Controller::Apic => {
+            let period = APIC_TICKS_IN_10MS.load(Relaxed) as u64;
+            countdown_delay(period * micros / 10_000, period, || unsafe {
+                lapic().read_register(LAPIC_TIMER_CURRCNT_REG) as u64
+            });
         }
+        Controller::Pic => {
+            let period = pit::DIVIDER_10MS as u64;
+            countdown_delay(pit::FREQUENCY as u64 * micros / 1_000_000, period, || {
+                pit::read_count() as u64
+            });
+        }
+    }
+}
 
-        millis -= 10;
+/// Durations `delay_test` tries, in microseconds.
+#[cfg(feature = "delay-test")]
+const DELAY_TEST_MICROS: [usize; 8] = [1, 10, 100, 500, 1000, 5000, 10_000, 40_000];
+
+/// Measure how long `udelay` actually takes against PIT channel 2 and
+/// print the error, to be run at boot.
+#[cfg(feature = "delay-test")]
+pub fn delay_test() {
+    sprintln!("udelay test against the PIT:");
+    for micros in DELAY_TEST_MICROS {
+        let Some(measured) = pit::measure_polled(|| udelay(micros)) else {
+            sprintln!("  {micros:>6}us: too long for the PIT");
+            continue;
+        };
+        let measured = measured.as_nanos() as i128;
+        let expected = micros as i128 * 1000;
+        let error = measured - expected;
+        sprintln!(
+            "  {micros:>6}us: took {measured:>8}ns, error {error:+}ns ({:+}ppm)",
+            error * 1_000_000 / expected,
+        );
     }
 }
 
-/// Simple spin delay
+/// Delay for `dur`. Delays under 1ms spin, longer ones halt until the timer
+/// wheel wakes us up.
 pub fn delay(dur: Duration) {
     if dur.as_micros() < 1000 {
         udelay(dur.as_micros() as usize);
     } else {
-        mdelay(
-            dur.as_millis()
-                .try_into()
-                .expect("delay duration is too long"),
-        );
+        sleep(dur);
     }
 }
@@ -0,0 +1,151 @@
+//! Programming the timer interrupt.
+//!
+//! With the LAPIC, the timer doesn't tick periodically. Instead it is armed
+//! for the earliest pending deadline only, either by counting down in one-shot
+//! mode or, if the CPU supports it, by comparing against the TSC directly in
+//! TSC-deadline mode. Idle CPUs thus stay halted until someone actually needs
+//! them to wake up.
+//!
+//! This requires a clock that keeps running without timer interrupts, so if
+//! `now` is derived from the IRQ count, or we only have the legacy PICs, the
+//! timer stays periodic at 100Hz.
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::Ordering::{Relaxed, SeqCst};
+use core::sync::atomic::{AtomicU64, fence};
+use core::time::Duration;
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+
+use super::instant::{ClockSource, clock_source};
+use super::{
+    APIC_TICKS_IN_10MS, Instant, LAPIC_LVT_TIMER_REG, LAPIC_TIMER_INITCNT_REG, lapic, tsc_frequency,
+};
+use crate::interrupts::InterruptIndex;
+use crate::interrupts::controller::{self, Controller};
+use crate::sprintln;
+
+/// The TSC value at which the timer fires in TSC-deadline mode, 0 disarms it.
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// LVT timer mode TSC-deadline.
+const APIC_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// How long to stay idle at most with a HPET clock, so that wrap arounds of
+/// its counter are noticed.
+const MAX_IDLE_HPET: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// Every 10ms.
+    Periodic,
+    /// LAPIC timer counting down to the next deadline.
+    OneShot,
+    /// LAPIC timer firing when the TSC reaches the next deadline.
+    TscDeadline,
+}
+
+static MODE: Once<Mode> = Once::new();
+
+/// The deadline the timer is armed for in nanoseconds, `u64::MAX` if none.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Whether the LAPIC timer supports TSC-deadline mode.
+pub fn tsc_deadline_supported() -> bool {
+    __cpuid(1).ecx & (1 << 24) != 0
+}
+
+/// Pick the timer mode. The timers must be calibrated and the clock source
+/// must be picked.
+pub(super) fn init() {
+    let mode = match (controller::controller(), clock_source()) {
+        (Controller::Pic, _) | (_, ClockSource::Ticks) => Mode::Periodic,
+        (Controller::Apic, ClockSource::Tsc) if tsc_deadline_supported() => Mode::TscDeadline,
+        (Controller::Apic, _) => Mode::OneShot,
+    };
+
+    if mode != Mode::Periodic {
+        let mut lapic = lapic();
+        let lvt = match mode {
+            Mode::TscDeadline => InterruptIndex::Timer as u32 | APIC_TIMER_TSC_DEADLINE,
+            _ => InterruptIndex::Timer as u32,
+        };
+        unsafe {
+            // stop the periodic timer.
+            lapic.write_register(LAPIC_TIMER_INITCNT_REG, 0);
+            lapic.write_register(LAPIC_LVT_TIMER_REG, lvt);
+        }
+    }
+
+    sprintln!("timer mode: {mode:?}");
+    MODE.call_once(|| mode);
+
+    if let Some(deadline) = idle_deadline() {
+        request_wakeup(deadline);
+    }
+}
+
+/// The timer is periodic until `init` is done with calibrating.
+pub fn mode() -> Mode {
+    MODE.get().copied().unwrap_or(Mode::Periodic)
+}
+
+/// When to wake up even though nobody asked to.
+fn idle_deadline() -> Option<Instant> {
+    (clock_source() == ClockSource::Hpet).then(|| Instant::now() + MAX_IDLE_HPET)
+}
+
+fn arm(deadline: Instant) {
+    let mut lapic = lapic();
+    match mode() {
+        Mode::TscDeadline => {
+            let tsc = deadline.as_nanos() as u128 * tsc_frequency() as u128 / 1_000_000_000;
+            // the write to the LVT must be ordered before the write to the MSR.
+            fence(SeqCst);
+            // 0 would disarm the timer.
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write((tsc as u64).max(1)) }
+        }
+        Mode::OneShot => {
+            let nanos = deadline
+                .saturating_duration_since(Instant::now())
+                .as_nanos();
+            let ticks = nanos * APIC_TICKS_IN_10MS.load(Relaxed) as u128 / 10_000_000;
+            // deadlines too far away wake us up early, and are armed again.
+            let ticks = ticks.clamp(1, u32::MAX as u128) as u32;
+            unsafe { lapic.write_register(LAPIC_TIMER_INITCNT_REG, ticks) }
+        }
+        Mode::Periodic => unreachable!(),
+    }
+}
+
+/// Make sure there is a timer interrupt at `deadline` or earlier.
+///
+/// Does nothing if the timer is periodic, the interrupt after `deadline`
+/// will have to do.
+pub fn request_wakeup(deadline: Instant) {
+    if mode() == Mode::Periodic {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let nanos = deadline.as_nanos();
+        if nanos < NEXT_DEADLINE.load(Relaxed) {
+            NEXT_DEADLINE.store(nanos, Relaxed);
+            arm(deadline);
+        }
+    });
+}
+
+/// Called from the timer interrupt handler.
+pub fn timer_interrupt() {
+    if mode() == Mode::Periodic {
+        return;
+    }
+
+    // whoever asked for this interrupt asks again if it came too early.
+    NEXT_DEADLINE.store(u64::MAX, Relaxed);
+    if let Some(deadline) = idle_deadline() {
+        request_wakeup(deadline);
+    }
+}
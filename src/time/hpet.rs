@@ -0,0 +1,184 @@
+//! The High Precision Event Timer (HPET).
+//!
+//! Only the main counter is used, as a monotonic clock and as the reference
+//! for calibrating the other timers. Its comparators are left disabled.
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+use core::time::Duration;
+
+use acpi::HpetInfo;
+use spin::Once;
+
+use super::{Tables, wheel};
+use crate::mem::mmio::{self, CacheMode};
+
+/// General capabilities and ID register.
+///
+/// Bits 32 to 63 are the period of the main counter in femtoseconds, bit 13
+/// is set if the main counter is 64 bits wide.
+const GENERAL_CAPABILITIES_REG: usize = 0x0;
+/// General configuration register.
+const GENERAL_CONFIG_REG: usize = 0x10;
+/// The main counter value register.
+const MAIN_COUNTER_REG: usize = 0xF0;
+
+/// General configuration: the main counter runs and timers may interrupt.
+const ENABLE_CNF: u64 = 1 << 0;
+/// General configuration: legacy replacement routing, which would take over
+/// the IRQs of the PIT and the RTC.
+const LEG_RT_CNF: u64 = 1 << 1;
+/// General capabilities: the main counter is 64 bits wide.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+
+/// The specification doesn't allow periods longer than 100ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+pub struct Hpet {
+    /// virtual memory pointer to the register block.
+    base: NonNull<u8>,
+    /// period of the main counter in femtoseconds.
+    period_fs: u64,
+    counter_64bit: bool,
+}
+
+// SAFETY: the pointer is to MMIO registers, which any processor can access.
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// For 32 bit main counters, the last value read extended to 64 bits.
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl Hpet {
+    #[inline]
+    unsafe fn read_register(&self, offset: usize) -> u64 {
+        self.base.as_ptr().add(offset).cast::<u64>().read_volatile()
+    }
+
+    #[inline]
+    unsafe fn write_register(&self, offset: usize, value: u64) {
+        self.base
+            .as_ptr()
+            .add(offset)
+            .cast::<u64>()
+            .write_volatile(value);
+    }
+
+    fn counter(&self) -> u64 {
+        if self.counter_64bit {
+            return unsafe { self.read_register(MAIN_COUNTER_REG) };
+        }
+
+        // extend the counter to 64 bits, which works as long as it is read at
+        // least once per wrap around (about 5 minutes at 14.3MHz), see
+        // `start_wrap_timer`.
+        let now = unsafe { self.read_register(MAIN_COUNTER_REG) } as u32;
+        let mut last = LAST_COUNTER.load(Relaxed);
+        loop {
+            let extended = last + now.wrapping_sub(last as u32) as u64;
+            match LAST_COUNTER.compare_exchange_weak(last, extended, Relaxed, Relaxed) {
+                Ok(_) => return extended,
+                Err(actual) => last = actual,
+            }
+        }
+    }
+}
+
+/// Find the HPET in the ACPI tables and start its main counter.
+///
+/// Use `available` to find out whether there is a usable HPET.
+pub fn init(tables: &Tables) {
+    let Ok(info) = HpetInfo::new(tables) else {
+        log::warn!("no HPET found, calibrating against the PIT");
+        return;
+    };
+
+    let hpet = Hpet {
+        base: mmio::map(
+            "hpet",
+            info.base_address as u64,
+            1024,
+            CacheMode::Uncacheable,
+        )
+        .expect("failed to map the HPET"),
+        period_fs: 0,
+        counter_64bit: false,
+    };
+
+    let capabilities = unsafe { hpet.read_register(GENERAL_CAPABILITIES_REG) };
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        log::warn!("HPET reports a bogus period of {period_fs}fs, ignoring it");
+        return;
+    }
+
+    let hpet = Hpet {
+        period_fs,
+        counter_64bit: capabilities & COUNT_SIZE_CAP != 0,
+        ..hpet
+    };
+
+    unsafe {
+        let config = hpet.read_register(GENERAL_CONFIG_REG);
+        // halt the counter while resetting it.
+        hpet.write_register(GENERAL_CONFIG_REG, config & !(ENABLE_CNF | LEG_RT_CNF));
+        hpet.write_register(MAIN_COUNTER_REG, 0);
+        hpet.write_register(GENERAL_CONFIG_REG, config & !LEG_RT_CNF | ENABLE_CNF);
+    }
+
+    log::info!(
+        "HPET at {:#x}, {}Hz, {} bit counter",
+        info.base_address,
+        FEMTOS_PER_SEC / period_fs,
+        if hpet.counter_64bit { 64 } else { 32 }
+    );
+
+    HPET.call_once(|| hpet);
+}
+
+/// Whether `init` found a usable HPET.
+pub fn available() -> bool {
+    HPET.get().is_some()
+}
+
+fn hpet() -> &'static Hpet {
+    HPET.get().expect("HPET not initialized")
+}
+
+/// Keep a 32 bit main counter extended to 64 bits while nothing else reads
+/// it, by reading it twice per wrap around. The timer wheel must be running.
+pub fn start_wrap_timer() {
+    let hpet = hpet();
+    if hpet.counter_64bit {
+        return;
+    }
+    let wrap_nanos = (1u128 << 32) * hpet.period_fs as u128 / 1_000_000;
+    wheel::schedule_periodic(Duration::from_nanos(wrap_nanos as u64 / 2), move || {
+        hpet.counter();
+    });
+}
+
+/// Nanoseconds since the HPET was initialized.
+pub fn nanos() -> u64 {
+    let hpet = hpet();
+    (hpet.counter() as u128 * hpet.period_fs as u128 / 1_000_000) as u64
+}
+
+/// Busy-wait for `dur` by polling the main counter.
+///
+/// `started` is called right after the first read of the counter, so
+/// whatever it starts runs for (almost) exactly `dur` once this returns.
+pub fn wait_polled(dur: Duration, started: impl FnOnce()) {
+    let hpet = hpet();
+    let ticks = (dur.as_nanos() * 1_000_000 / hpet.period_fs as u128) as u64;
+
+    let start = hpet.counter();
+    started();
+    while hpet.counter() - start < ticks {
+        core::hint::spin_loop();
+    }
+}
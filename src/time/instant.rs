@@ -0,0 +1,205 @@
+//! A monotonic clock with nanosecond resolution.
+//!
+//! Backed by the TSC if it is invariant, i.e. runs at a constant rate
+//! regardless of power states. Otherwise by the HPET, and as a last resort by
+//! the timer IRQ count interpolated with the current count of the timer.
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+use core::time::Duration;
+
+use spin::Once;
+
+use super::selftest::tsc_unreliable;
+use super::{APIC_TICKS_IN_10MS, LAPIC_TIMER_CURRCNT_REG, get_irq_cnt, hpet, lapic, pit};
+use crate::interrupts::controller::{self, Controller};
+use crate::sprintln;
+use crate::utils::rdtsc;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const NANOS_PER_TICK: u64 = 10_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClockSource {
+    Tsc,
+    Hpet,
+    /// Timer IRQs plus the current count of the LAPIC timer or the PIT.
+    Ticks,
+}
+
+static SOURCE: Once<ClockSource> = Once::new();
+
+/// The latest time returned by `now`, so that it never goes backwards.
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the TSC runs at a constant rate in all ACPI P-, C- and T-states.
+pub fn tsc_is_invariant() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    max_extended_leaf >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Pick the clock source. The timers must be calibrated.
+pub(super) fn init() {
+    let source = if tsc_is_invariant() && super::tsc_frequency() != 0 && !tsc_unreliable() {
+        ClockSource::Tsc
+    } else if hpet::available() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Ticks
+    };
+    sprintln!("clock source: {source:?}");
+    SOURCE.call_once(|| source);
+}
+
+pub fn clock_source() -> ClockSource {
+    *SOURCE.get().expect("clock not initialized")
+}
+
+/// Nanoseconds from the timer IRQ count and the progress towards the next IRQ.
+fn ticks_nanos() -> u64 {
+    loop {
+        let irqs = get_irq_cnt();
+        let (elapsed, period) = match controller::controller() {
+            Controller::Apic => {
+                let period = APIC_TICKS_IN_10MS.load(Relaxed);
+                let count = unsafe { lapic().read_register(LAPIC_TIMER_CURRCNT_REG) };
+                (period.saturating_sub(count) as u64, period as u64)
+            }
+            Controller::Pic => {
+                let period = pit::DIVIDER_10MS;
+                (
+                    period.saturating_sub(pit::read_count()) as u64,
+                    period as u64,
+                )
+            }
+        };
+        // retry if an IRQ came in between.
+        if get_irq_cnt() == irqs {
+            return irqs.0 as u64 * NANOS_PER_TICK + elapsed * NANOS_PER_TICK / period;
+        }
+    }
+}
+
+fn now_nanos() -> u64 {
+    let nanos = match clock_source() {
+        ClockSource::Tsc => {
+            (rdtsc() as u128 * NANOS_PER_SEC as u128 / super::tsc_frequency() as u128) as u64
+        }
+        ClockSource::Hpet => hpet::nanos(),
+        ClockSource::Ticks => ticks_nanos(),
+    };
+    // the counter may have wrapped while an IRQ was pending, which would make
+    // the time jump back by one tick.
+    LAST_NANOS.fetch_max(nanos, Relaxed).max(nanos)
+}
+
+/// A point in time, measured from an unspecified point during boot.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Instant {
+    nanos: u64,
+}
+
+/// The current time.
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Self { nanos: now_nanos() }
+    }
+
+    pub(super) fn from_nanos(nanos: u64) -> Self {
+        Self { nanos }
+    }
+
+    pub(super) fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    /// Time since boot, or rather since the clock started counting.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// Panics if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .expect("supplied instant is later than self")
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos
+            .checked_sub(earlier.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().saturating_duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Self {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Self {
+            nanos: self.nanos.checked_sub(nanos)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instant({:?})", self.since_boot())
+    }
+}
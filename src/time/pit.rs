@@ -0,0 +1,113 @@
+//! The programmable interval timer (PIT).
+//!
+//! Channel 0 is wired to ISA IRQ 0, channel 2's gate and output can be
+//! controlled and read through system control port B, which lets us wait for
+//! a fixed amount of time without any interrupts.
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// The PIT's input clock in Hz.
+pub const FREQUENCY: usize = 1_193_182;
+
+/// The divider for 100Hz which is 10ms per IRQ.
+pub const DIVIDER_10MS: u16 = 11932;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+
+/// System control port B.
+const PORT_B: u16 = 0x61;
+/// Port B: the gate input of channel 2.
+const PORT_B_CHANNEL2_GATE: u8 = 1 << 0;
+/// Port B: connects channel 2 to the PC speaker.
+const PORT_B_SPEAKER: u8 = 1 << 1;
+/// Port B: the output of channel 2 (read only).
+const PORT_B_CHANNEL2_OUT: u8 = 1 << 5;
+
+/// Configure channel 0 to send an IRQ every 10ms.
+pub fn start_periodic() {
+    let mut channel0 = Port::new(CHANNEL0);
+    unsafe {
+        // select channel 0, access mode lobyte/hibyte, mode 2 rate generator
+        Port::new(COMMAND).write(0b00110100u8);
+
+        // send the lo/hi bytes to set the reload value.
+        channel0.write(DIVIDER_10MS as u8);
+        channel0.write((DIVIDER_10MS >> 8) as u8);
+    }
+}
+
+/// Read the current count of channel 0.
+pub fn read_count() -> u16 {
+    let mut channel0 = Port::<u8>::new(CHANNEL0);
+    without_interrupts(|| unsafe {
+        // latch the count of channel 0 so that the two reads are consistent.
+        Port::new(COMMAND).write(0u8);
+        let lo = channel0.read() as u16;
+        let hi = channel0.read() as u16;
+        hi << 8 | lo
+    })
+}
+
+/// Wait 10ms by polling channel 2, without using any interrupts.
+///
+/// `started` is called right after channel 2 starts counting, so whatever
+/// it starts runs for (almost) exactly 10ms once this returns.
+pub fn wait_10ms_polled(started: impl FnOnce()) {
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut channel2 = Port::<u8>::new(CHANNEL2);
+    unsafe {
+        let saved = port_b.read();
+        // enable the gate of channel 2, but keep the speaker quiet.
+        port_b.write(saved & !PORT_B_SPEAKER | PORT_B_CHANNEL2_GATE);
+
+        // select channel 2, access mode lobyte/hibyte, mode 0 interrupt on
+        // terminal count, which only sets the output once the count reaches 0.
+        Port::new(COMMAND).write(0b10110000u8);
+        channel2.write(DIVIDER_10MS as u8);
+        // counting starts once the high byte is written.
+        channel2.write((DIVIDER_10MS >> 8) as u8);
+        started();
+
+        while port_b.read() & PORT_B_CHANNEL2_OUT == 0 {
+            core::hint::spin_loop();
+        }
+
+        port_b.write(saved);
+    }
+}
+
+/// Measure how long `f` takes using channel 2, without using any
+/// interrupts.
+///
+/// Returns `None` if it took longer than a full count of channel 2 (about
+/// 55ms).
+#[cfg(feature = "delay-test")]
+pub fn measure_polled(f: impl FnOnce()) -> Option<core::time::Duration> {
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut channel2 = Port::<u8>::new(CHANNEL2);
+    let mut command = Port::<u8>::new(COMMAND);
+    unsafe {
+        let saved = port_b.read();
+        port_b.write(saved & !PORT_B_SPEAKER | PORT_B_CHANNEL2_GATE);
+
+        // mode 0 again, but from the largest count so the output stays low
+        // for as long as possible.
+        command.write(0b10110000);
+        channel2.write(0xFF);
+        channel2.write(0xFF);
+        f();
+
+        // latch the count of channel 2.
+        command.write(0b10000000);
+        let lo = channel2.read() as u64;
+        let hi = channel2.read() as u64;
+        let overflowed = port_b.read() & PORT_B_CHANNEL2_OUT != 0;
+        port_b.write(saved);
+
+        let ticks = 0xFFFF - (hi << 8 | lo);
+        (!overflowed)
+            .then(|| core::time::Duration::from_nanos(ticks * 1_000_000_000 / FREQUENCY as u64))
+    }
+}
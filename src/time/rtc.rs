@@ -0,0 +1,283 @@
+//! The CMOS real-time clock (RTC) and wall-clock time.
+//!
+//! The RTC is only read at boot and, if its update IRQ is enabled, once a
+//! second right after it updated. In between, the wall-clock time advances
+//! with the monotonic clock.
+use core::fmt;
+use core::sync::atomic::Ordering::Relaxed;
+use core::sync::atomic::{AtomicU8, AtomicU64};
+
+use acpi::fadt::Fadt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use super::{Instant, Tables};
+use crate::interrupts::controller;
+use crate::sprintln;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+/// Reading it acknowledges the IRQ, there won't be another one until then.
+const REG_STATUS_C: u8 = 0x0C;
+
+/// Status A: an update is in progress, the time registers may be garbage.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: values are binary instead of BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Status B: hours are 0 to 23 instead of 1 to 12 with bit 7 set for PM.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status B: IRQ 8 after every update.
+const STATUS_B_UPDATE_ENDED_IRQ: u8 = 1 << 4;
+/// Status C: the IRQ was raised because an update ended.
+const STATUS_C_UPDATE_ENDED: u8 = 1 << 4;
+
+const RTC_IRQ: u8 = 8;
+
+/// Serializes access to the CMOS index and data ports.
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// CMOS register with the century, from the FADT. 0 if there is none.
+static CENTURY_REG: AtomicU8 = AtomicU8::new(0);
+
+/// Seconds since the unix epoch at `SYNC_NANOS`.
+static SYNC_UNIX: AtomicU64 = AtomicU64::new(0);
+/// The monotonic time when the RTC read `SYNC_UNIX`, in nanoseconds.
+static SYNC_NANOS: AtomicU64 = AtomicU64::new(0);
+
+fn read_cmos(reg: u8) -> u8 {
+    unsafe {
+        // bit 7 would disable NMIs.
+        Port::new(CMOS_INDEX).write(reg & 0x7F);
+        Port::new(CMOS_DATA).read()
+    }
+}
+
+fn write_cmos(reg: u8, value: u8) {
+    unsafe {
+        Port::new(CMOS_INDEX).write(reg & 0x7F);
+        Port::new(CMOS_DATA).write(value);
+    }
+}
+
+/// A date and time in UTC, or whatever the RTC is set to.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+///
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil`.
+///
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn to_unix(self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        let secs = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        (days * 86400 + secs) as u64
+    }
+
+    pub fn from_unix(secs: u64) -> Self {
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        let secs_of_day = secs % 86400;
+        Self {
+            year: year as u16,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The raw time registers.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_registers(century_reg: u8) -> Registers {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Registers {
+        second: read_cmos(REG_SECONDS),
+        minute: read_cmos(REG_MINUTES),
+        hour: read_cmos(REG_HOURS),
+        day: read_cmos(REG_DAY),
+        month: read_cmos(REG_MONTH),
+        year: read_cmos(REG_YEAR),
+        century: if century_reg != 0 {
+            read_cmos(century_reg)
+        } else {
+            0
+        },
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xF)
+}
+
+/// Read the RTC.
+pub fn read() -> DateTime {
+    let century_reg = CENTURY_REG.load(Relaxed);
+    without_interrupts(|| {
+        let _cmos = CMOS.lock();
+
+        // an update may start right after we checked that there is none, so
+        // read until we get the same values twice.
+        let mut regs = read_registers(century_reg);
+        loop {
+            let again = read_registers(century_reg);
+            if again == regs {
+                break;
+            }
+            regs = again;
+        }
+
+        let status_b = read_cmos(REG_STATUS_B);
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let convert = |value: u8| if binary { value } else { from_bcd(value) };
+
+        // in 12 hour mode, bit 7 of the hours is set for PM.
+        let pm = status_b & STATUS_B_24_HOUR == 0 && regs.hour & 0x80 != 0;
+        let mut hour = convert(regs.hour & 0x7F);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12 AM is midnight, 12 PM is noon.
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year = convert(regs.year) as u16;
+        let year = if century_reg != 0 {
+            convert(regs.century) as u16 * 100 + year
+        } else {
+            // without a century register, assume we're in the 21st century.
+            2000 + year
+        };
+
+        DateTime {
+            year,
+            month: convert(regs.month),
+            day: convert(regs.day),
+            hour,
+            minute: convert(regs.minute),
+            second: convert(regs.second),
+        }
+    })
+}
+
+/// Make the wall-clock time continue from what the RTC says now.
+fn sync() {
+    let unix = read().to_unix();
+    let nanos = Instant::now().since_boot().as_nanos() as u64;
+    without_interrupts(|| {
+        SYNC_UNIX.store(unix, Relaxed);
+        SYNC_NANOS.store(nanos, Relaxed);
+    });
+}
+
+/// Find the century register and read the RTC. The monotonic clock must work.
+pub fn init(tables: &Tables) {
+    if let Ok(fadt) = tables.find_table::<Fadt>() {
+        CENTURY_REG.store(fadt.century, Relaxed);
+    }
+    sync();
+    sprintln!("RTC: {}", read());
+}
+
+/// Resynchronize the wall-clock time with the RTC after each of its updates,
+/// i.e. at the start of every second, using IRQ 8.
+pub fn enable_update_irq() {
+    let vector = controller::enable_isa_irq(RTC_IRQ, "rtc", |_| {
+        let status_c = without_interrupts(|| {
+            let _cmos = CMOS.lock();
+            read_cmos(REG_STATUS_C)
+        });
+        if status_c & STATUS_C_UPDATE_ENDED != 0 {
+            sync();
+        }
+    });
+    if vector.is_none() {
+        log::warn!("RTC: no vector for IRQ {RTC_IRQ}");
+        return;
+    }
+
+    without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        let status_b = read_cmos(REG_STATUS_B);
+        write_cmos(REG_STATUS_B, status_b | STATUS_B_UPDATE_ENDED_IRQ);
+        // get rid of a pending IRQ, or there won't be another.
+        read_cmos(REG_STATUS_C);
+    });
+}
+
+/// The current wall-clock time.
+pub fn now() -> DateTime {
+    let (unix, nanos) = without_interrupts(|| (SYNC_UNIX.load(Relaxed), SYNC_NANOS.load(Relaxed)));
+    let elapsed = (Instant::now().since_boot().as_nanos() as u64).saturating_sub(nanos);
+    DateTime::from_unix(unix + elapsed / 1_000_000_000)
+}
@@ -0,0 +1,173 @@
+//! Cross-checking the timers after calibration.
+//!
+//! Calibration trusts a single measurement against a single reference. Here
+//! we measure the LAPIC timer and the TSC against every reference we have a
+//! few more times, report how much the results jitter and how much the
+//! references disagree, and replace the calibration with the results of the
+//! most consistent reference if they differ too much.
+//!
+//! Under a hypervisor, all of this is less meaningful: the PIT and the HPET
+//! are emulated and the virtual CPU may be descheduled in the middle of a
+//! measurement.
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::Relaxed;
+
+use super::instant::tsc_is_invariant;
+use super::{Lapic, Measurement, calibrate_with_hpet, calibrate_with_pit_polled, hpet};
+use crate::sprintln;
+
+const ROUNDS: usize = 5;
+
+/// Jitter above this makes a reference untrustworthy.
+const MAX_JITTER_PPM: u64 = 2000;
+
+/// If the most trustworthy reference differs from the calibration by more
+/// than this, it replaces the calibration.
+const MAX_DISAGREEMENT_PPM: u64 = 1000;
+
+/// Set if the TSC rate jittered too much to base the clock on it.
+static TSC_UNRELIABLE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the self-test found the TSC too unstable to be used as a clock.
+pub fn tsc_unreliable() -> bool {
+    TSC_UNRELIABLE.load(Relaxed)
+}
+
+/// The vendor signature of the hypervisor we're running under, if any.
+pub fn hypervisor() -> Option<[u8; 12]> {
+    // the "hypervisor present" bit, which real CPUs leave clear.
+    if __cpuid(1).ecx & (1 << 31) == 0 {
+        return None;
+    }
+    let leaf = __cpuid(0x4000_0000);
+    let mut vendor = [0; 12];
+    vendor[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor[8..].copy_from_slice(&leaf.edx.to_le_bytes());
+    Some(vendor)
+}
+
+/// Mean and jitter (the spread relative to the mean) of some samples.
+struct Summary {
+    mean: u64,
+    jitter_ppm: u64,
+}
+
+impl Summary {
+    fn new(samples: impl Iterator<Item = u64> + Clone) -> Self {
+        let mean = samples.clone().sum::<u64>() / ROUNDS as u64;
+        let min = samples.clone().min().unwrap_or(0);
+        let max = samples.max().unwrap_or(0);
+        Self {
+            mean,
+            jitter_ppm: ppm(max - min, mean),
+        }
+    }
+}
+
+fn ppm(delta: u64, of: u64) -> u64 {
+    if of == 0 {
+        return 0;
+    }
+    (delta as u128 * 1_000_000 / of as u128) as u64
+}
+
+/// The results of measuring against one reference.
+struct Series {
+    name: &'static str,
+    lapic: Summary,
+    tsc: Summary,
+}
+
+impl Series {
+    fn measure(name: &'static str, measure: impl Fn() -> Measurement) -> Self {
+        let samples: [Measurement; ROUNDS] = core::array::from_fn(|_| measure());
+        Self {
+            name,
+            lapic: Summary::new(samples.iter().map(|m| m.lapic_ticks as u64)),
+            tsc: Summary::new(samples.iter().map(|m| m.tsc_cycles)),
+        }
+    }
+
+    /// The larger of both jitters, so a reference is only as good as its
+    /// worst result.
+    fn jitter_ppm(&self, lapic: bool) -> u64 {
+        if lapic {
+            self.lapic.jitter_ppm.max(self.tsc.jitter_ppm)
+        } else {
+            self.tsc.jitter_ppm
+        }
+    }
+}
+
+/// Check `calibrated` against all references, returns the calibration to
+/// use, per 10ms.
+pub(super) fn run(lapic: Option<Lapic>, calibrated: Measurement) -> Measurement {
+    let vendor = hypervisor();
+    if let Some(vendor) = vendor {
+        let vendor = core::str::from_utf8(&vendor).unwrap_or("?");
+        sprintln!("running under a hypervisor ({vendor}), timer measurements may be unreliable");
+    }
+
+    let mut series = alloc::vec![Series::measure("PIT", || calibrate_with_pit_polled(lapic))];
+    if hpet::available() {
+        series.push(Series::measure("HPET", || calibrate_with_hpet(lapic, 10)));
+    }
+
+    sprintln!("timer self-test, {ROUNDS} rounds of 10ms:");
+    for s in &series {
+        sprintln!(
+            "  {:<4}: lapic {:>10} ticks (jitter {:>6}ppm), tsc {:>10} cycles (jitter {:>6}ppm)",
+            s.name,
+            s.lapic.mean,
+            s.lapic.jitter_ppm,
+            s.tsc.mean,
+            s.tsc.jitter_ppm
+        );
+    }
+    if let [a, b] = &series[..] {
+        sprintln!(
+            "  {} and {} disagree by {}ppm (lapic), {}ppm (tsc)",
+            a.name,
+            b.name,
+            ppm(a.lapic.mean.abs_diff(b.lapic.mean), b.lapic.mean),
+            ppm(a.tsc.mean.abs_diff(b.tsc.mean), b.tsc.mean)
+        );
+    }
+
+    // on ties, prefer the HPET which comes last.
+    let best = series
+        .iter()
+        .rev()
+        .min_by_key(|s| s.jitter_ppm(lapic.is_some()))
+        .unwrap();
+    if best.jitter_ppm(lapic.is_some()) > MAX_JITTER_PPM {
+        log::warn!("all timer references jitter a lot, keeping the calibration");
+    }
+
+    if best.tsc.jitter_ppm > MAX_JITTER_PPM && (vendor.is_some() || !tsc_is_invariant()) {
+        log::warn!("the TSC rate is unstable, not using it as a clock");
+        TSC_UNRELIABLE.store(true, Relaxed);
+    }
+
+    let lapic_off = ppm(
+        best.lapic.mean.abs_diff(calibrated.lapic_ticks as u64),
+        best.lapic.mean,
+    );
+    let tsc_off = ppm(best.tsc.mean.abs_diff(calibrated.tsc_cycles), best.tsc.mean);
+    if best.jitter_ppm(lapic.is_some()) <= MAX_JITTER_PPM
+        && lapic_off.max(tsc_off) > MAX_DISAGREEMENT_PPM
+    {
+        log::warn!(
+            "calibration is off by {lapic_off}ppm (lapic), {tsc_off}ppm (tsc), using the {} instead",
+            best.name
+        );
+        return Measurement {
+            lapic_ticks: best.lapic.mean as u32,
+            tsc_cycles: best.tsc.mean,
+        };
+    }
+
+    calibrated
+}
@@ -0,0 +1,308 @@
+//! Software timers on a hierarchical timer wheel.
+//!
+//! Time is divided into ticks of 1ms. Level 0 has a slot for each of the next
+//! 64 ticks, every slot of level 1 covers 64 ticks, every slot of level 2
+//! 64 * 64 ticks and so on. Whenever level 0 wrapped around, the timers of
+//! the next slot of level 1 are spread over level 0 again (and likewise for
+//! the higher levels), so that scheduling and expiring timers doesn't depend
+//! on how many there are. A bitmap of the occupied slots of each level lets
+//! us find the next tick at which anything happens without looking at the
+//! timers, or at the empty ticks in between.
+//!
+//! Callbacks run in the timer interrupt handler, so they must be short and
+//! may not block.
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use core::sync::atomic::{AtomicBool, AtomicU64};
+use core::time::Duration;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{Instant, clockevent};
+
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+
+/// Ticks further away are put into the last level, and are moved around
+/// on it until they are close enough.
+const MAX_DELTA: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+
+const NANOS_PER_TICK: u64 = 1_000_000;
+
+pub type Callback = Box<dyn FnMut() + Send>;
+
+/// Identifies a scheduled timer, for cancelling it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TimerId(u64);
+
+struct Timer {
+    id: TimerId,
+    /// The tick at which the timer expires.
+    expires: u64,
+    /// Period in ticks, if the timer is periodic.
+    period: Option<u64>,
+    callback: Callback,
+}
+
+struct Wheel {
+    /// The last tick that was processed.
+    now: u64,
+    levels: [[Vec<Timer>; SLOTS]; LEVELS],
+    /// Bit `n` is set if slot `n` of the level has timers.
+    occupied: [u64; LEVELS],
+    /// Timers whose callbacks are about to run, in order of expiry.
+    expired: VecDeque<Timer>,
+    /// The timer whose callback is currently running, and whether it was
+    /// cancelled meanwhile.
+    running: Option<(TimerId, bool)>,
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the clock works, timer interrupts before that are ignored.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn tick_of(instant: Instant) -> u64 {
+    instant.as_nanos() / NANOS_PER_TICK
+}
+
+/// Ticks needed to cover `dur`, rounded up so that timers never expire early.
+fn ticks_of(dur: Duration) -> u64 {
+    dur.as_nanos().div_ceil(NANOS_PER_TICK as u128) as u64
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            now: 0,
+            levels: [const { [const { Vec::new() }; SLOTS] }; LEVELS],
+            occupied: [0; LEVELS],
+            expired: VecDeque::new(),
+            running: None,
+        }
+    }
+
+    /// `timer` must not expire before the current tick, see `insert_due`.
+    fn insert(&mut self, timer: Timer) {
+        let delta = timer.expires - self.now;
+        let (level, slot) = if delta >= MAX_DELTA {
+            let level = LEVELS - 1;
+            let expires = self.now + MAX_DELTA - 1;
+            (level, expires >> (SLOT_BITS * level as u32))
+        } else {
+            // the first level where the delta fits into the slots.
+            let level = (0..LEVELS)
+                .find(|&level| delta < 1 << (SLOT_BITS * (level as u32 + 1)))
+                .unwrap();
+            (level, timer.expires >> (SLOT_BITS * level as u32))
+        };
+
+        let slot = slot as usize % SLOTS;
+        self.levels[level][slot].push(timer);
+        self.occupied[level] |= 1 << slot;
+    }
+
+    /// Take all timers out of a slot.
+    fn take(&mut self, level: usize, slot: usize) -> Vec<Timer> {
+        self.occupied[level] &= !(1 << slot);
+        mem::take(&mut self.levels[level][slot])
+    }
+
+    /// Like `insert`, but timers that are already due expire with the next
+    /// tick.
+    fn insert_due(&mut self, mut timer: Timer) {
+        timer.expires = timer.expires.max(self.now + 1);
+        self.insert(timer);
+    }
+
+    fn remove(&mut self, id: TimerId) -> Option<Timer> {
+        if let Some(idx) = self.expired.iter().position(|timer| timer.id == id) {
+            return self.expired.remove(idx);
+        }
+        for level in 0..LEVELS {
+            for slot in 0..SLOTS {
+                let timers = &mut self.levels[level][slot];
+                if let Some(idx) = timers.iter().position(|timer| timer.id == id) {
+                    let timer = timers.swap_remove(idx);
+                    if timers.is_empty() {
+                        self.occupied[level] &= !(1 << slot);
+                    }
+                    return Some(timer);
+                }
+            }
+        }
+        None
+    }
+
+    /// The next tick at which the timers of an occupied slot of `level`
+    /// expire (level 0) or move to a lower level.
+    fn next_event_on(&self, level: usize) -> Option<u64> {
+        if self.occupied[level] == 0 {
+            return None;
+        }
+        // the slots come around again every `SLOTS` steps of the level.
+        let shift = SLOT_BITS * level as u32;
+        let step = (self.now >> shift) + 1;
+        let slots_away = self.occupied[level]
+            .rotate_right((step % SLOTS as u64) as u32)
+            .trailing_zeros();
+        Some((step + slots_away as u64) << shift)
+    }
+
+    /// The next tick at which a timer expires or has to move to a lower
+    /// level.
+    fn next_event(&self) -> Option<u64> {
+        (0..LEVELS)
+            .filter_map(|level| self.next_event_on(level))
+            .min()
+    }
+
+    /// Process all ticks up to `to`, moving the expired timers to `expired`.
+    ///
+    /// Only the ticks at which something happens are looked at, so this is
+    /// quick even after the timer interrupt stayed away for a long time.
+    fn advance(&mut self, to: u64) {
+        while let Some(tick) = self.next_event().filter(|&tick| tick <= to) {
+            self.now = tick;
+
+            // spread the timers of the higher levels over the lower ones,
+            // starting at the top, whenever the level below wrapped around.
+            // they expire at this tick or later.
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level as u32;
+                if tick & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = (tick >> shift) as usize % SLOTS;
+                for timer in self.take(level, slot) {
+                    self.insert(timer);
+                }
+            }
+
+            let timers = self.take(0, tick as usize % SLOTS);
+            self.expired.extend(timers);
+        }
+        self.now = self.now.max(to);
+    }
+}
+
+fn with_wheel<R>(f: impl FnOnce(&mut Wheel) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut WHEEL.lock()))
+}
+
+/// Make sure the timer interrupt comes in time for the next timer.
+fn request_wakeup(wheel: &Wheel) {
+    if let Some(tick) = wheel.next_event() {
+        clockevent::request_wakeup(Instant::from_nanos(tick * NANOS_PER_TICK));
+    }
+}
+
+/// Start processing timers. The clock must be initialized.
+pub(super) fn init() {
+    with_wheel(|wheel| wheel.now = tick_of(Instant::now()));
+    ENABLED.store(true, Release);
+}
+
+fn schedule_inner(after: Duration, period: Option<Duration>, callback: Callback) -> TimerId {
+    let id = TimerId(NEXT_ID.fetch_add(1, Relaxed));
+    let expires = tick_of(Instant::now()) + ticks_of(after);
+    let period = period.map(|period| ticks_of(period).max(1));
+    with_wheel(|wheel| {
+        wheel.insert_due(Timer {
+            id,
+            expires,
+            period,
+            callback,
+        });
+        request_wakeup(wheel);
+    });
+    id
+}
+
+/// Run `callback` once after `after`.
+pub fn schedule<F>(after: Duration, callback: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    schedule_inner(after, None, Box::new(callback))
+}
+
+/// Run `callback` every `period`, starting after one period.
+pub fn schedule_periodic<F>(period: Duration, callback: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    schedule_inner(period, Some(period), Box::new(callback))
+}
+
+/// Cancel a timer. Returns `false` if its callback already ran (unless it is
+/// periodic) or it was cancelled before.
+///
+/// Timers that expired but whose callbacks haven't run yet are cancelled as
+/// well. A callback may cancel its own timer.
+// nothing cancels timers yet.
+#[allow(dead_code)]
+pub fn cancel(id: TimerId) -> bool {
+    with_wheel(|wheel| match &mut wheel.running {
+        Some((running, cancelled)) if *running == id => !mem::replace(cancelled, true),
+        _ => wheel.remove(id).is_some(),
+    })
+}
+
+/// Run the callbacks of the expired timers. Called from the timer interrupt
+/// handler.
+pub fn run() {
+    if !ENABLED.load(Acquire) {
+        return;
+    }
+
+    let now = tick_of(Instant::now());
+    with_wheel(|wheel| wheel.advance(now));
+
+    // take them one by one, so that callbacks can cancel the ones after them.
+    let next = || {
+        with_wheel(|wheel| {
+            let timer = wheel.expired.pop_front()?;
+            wheel.running = Some((timer.id, false));
+            Some(timer)
+        })
+    };
+    while let Some(mut timer) = next() {
+        // the lock isn't held, so callbacks can schedule timers themselves.
+        (timer.callback)();
+        with_wheel(|wheel| {
+            let (_, cancelled) = wheel.running.take().unwrap();
+            if let (Some(period), false) = (timer.period, cancelled) {
+                timer.expires += period;
+                wheel.insert_due(timer);
+            }
+        });
+    }
+
+    with_wheel(|wheel| request_wakeup(wheel));
+}
+
+/// Halt until `dur` has passed. Interrupts must be enabled.
+pub fn sleep(dur: Duration) {
+    let done = Arc::new(AtomicBool::new(false));
+    let done2 = done.clone();
+    schedule(dur, move || done2.store(true, Release));
+
+    loop {
+        interrupts::disable();
+        if done.load(Acquire) {
+            interrupts::enable();
+            return;
+        }
+        // nothing can come in between enabling interrupts and halting.
+        interrupts::enable_and_hlt();
+    }
+}
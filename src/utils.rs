@@ -4,3 +4,9 @@ pub fn hlt_loop() -> ! {
         x86_64::instructions::hlt()
     }
 }
+
+/// Read the time stamp counter.
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}